@@ -96,6 +96,14 @@ pub(crate) fn build_router(state: AppState) -> Router {
     let api_routes = Router::new()
         .route("/merge", post(handlers::api::merge))
         .route("/npages", post(handlers::api::npages))
+        .route("/inspect", post(handlers::api::inspect))
+        .route("/split", post(handlers::api::split))
+        .route("/thumbnails", post(handlers::api::thumbnails))
+        .route("/images", post(handlers::api::images))
+        .route("/text", post(handlers::api::text))
+        .route("/form-fields", post(handlers::api::form_fields))
+        .route("/fill-form", post(handlers::api::fill_form))
+        .route("/repair", post(handlers::api::repair))
         .route_layer(api_governor);
 
     Router::new()
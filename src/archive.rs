@@ -0,0 +1,41 @@
+use std::io::Write;
+use std::path::PathBuf;
+
+use tempfile::TempDir;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::error::AppError;
+
+pub(crate) async fn zip_files(
+    tmp: &TempDir,
+    entries: Vec<(String, PathBuf)>,
+) -> Result<PathBuf, AppError> {
+    let out_path = tmp
+        .path()
+        .join(format!("archive_{}.zip", uuid::Uuid::new_v4()));
+
+    let zip_path = out_path.clone();
+    tokio::task::spawn_blocking(move || -> Result<(), AppError> {
+        let file =
+            std::fs::File::create(&zip_path).map_err(|e| AppError::Internal(e.to_string()))?;
+        let mut zip = ZipWriter::new(file);
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+
+        for (name, path) in entries {
+            let data = std::fs::read(&path).map_err(|e| AppError::Internal(e.to_string()))?;
+            zip.start_file(name, options)
+                .map_err(|e| AppError::Internal(format!("zip failed: {e}")))?;
+            zip.write_all(&data)
+                .map_err(|e| AppError::Internal(e.to_string()))?;
+        }
+
+        zip.finish()
+            .map_err(|e| AppError::Internal(format!("zip failed: {e}")))?;
+        Ok(())
+    })
+    .await
+    .map_err(|e| AppError::Internal(e.to_string()))??;
+
+    Ok(out_path)
+}
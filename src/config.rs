@@ -1,4 +1,5 @@
 use std::env;
+use std::path::PathBuf;
 use std::time::Duration;
 
 #[derive(Clone, Copy, Debug)]
@@ -16,6 +17,8 @@ pub(crate) struct AppConfig {
     pub(crate) process_timeout: Duration,
     pub(crate) cookie_secure: CookieSecureMode,
     pub(crate) trust_proxy_headers: bool,
+    pub(crate) pdfa_icc_profile: PathBuf,
+    pub(crate) verapdf_path: Option<PathBuf>,
 }
 
 impl AppConfig {
@@ -27,6 +30,13 @@ impl AppConfig {
         let process_timeout = env_u64_or("EXTERNAL_PROCESS_TIMEOUT_SECS", 120);
         let cookie_secure = cookie_secure_mode_from_env();
         let trust_proxy_headers = env_bool_or("TRUST_PROXY_HEADERS", false);
+        let pdfa_icc_profile = env::var("PDFA_ICC_PROFILE")
+            .ok()
+            .filter(|v| !v.trim().is_empty())
+            .unwrap_or_else(|| "/usr/share/color/icc/ghostscript/srgb.icc".to_string());
+        let verapdf_path = env::var_os("VERAPDF_PATH")
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from);
 
         Self {
             username,
@@ -36,6 +46,8 @@ impl AppConfig {
             process_timeout: Duration::from_secs(process_timeout),
             cookie_secure,
             trust_proxy_headers,
+            pdfa_icc_profile: PathBuf::from(pdfa_icc_profile),
+            verapdf_path,
         }
     }
 }
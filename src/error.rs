@@ -6,6 +6,7 @@ use tracing::error;
 pub(crate) enum AppError {
     Unauthorized,
     BadRequest(String),
+    Unprocessable(String),
     Internal(String),
 }
 
@@ -14,6 +15,7 @@ impl IntoResponse for AppError {
         match self {
             AppError::Unauthorized => (StatusCode::UNAUTHORIZED, "Unauthorized").into_response(),
             AppError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg).into_response(),
+            AppError::Unprocessable(msg) => (StatusCode::UNPROCESSABLE_ENTITY, msg).into_response(),
             AppError::Internal(msg) => {
                 error!("{msg}");
                 (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response()
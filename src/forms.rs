@@ -0,0 +1,737 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::error::AppError;
+use crate::qpdf_json::{self, object_dict, resolve};
+
+// Annotation flag bits (PDF 32000-1, 12.5.3).
+const FLAG_HIDDEN: i64 = 1 << 1;
+const FLAG_NO_VIEW: i64 = 1 << 5;
+
+// Form field flags (PDF 32000-1, 12.7.3.1 and 12.7.4.4).
+const FIELD_READ_ONLY: i64 = 1;
+const FIELD_REQUIRED: i64 = 1 << 1;
+const FIELD_COMBO: i64 = 1 << 17;
+const FIELD_EDIT: i64 = 1 << 18;
+const FIELD_MULTI_SELECT: i64 = 1 << 21;
+const FIELD_COMB: i64 = 1 << 24;
+
+// Marker holding an annotation's original /F while it is hidden from flattening.
+const SAVED_FLAGS_KEY: &str = "/PdfToolsSavedF";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum FlattenMode {
+    Annotations,
+    Forms,
+    All,
+}
+
+impl FlattenMode {
+    pub(crate) fn parse(s: &str) -> Result<Self, AppError> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "annotations" => Ok(Self::Annotations),
+            "forms" => Ok(Self::Forms),
+            "all" => Ok(Self::All),
+            other => Err(AppError::BadRequest(format!(
+                "Invalid flatten mode: {other} (expected annotations, forms or all)"
+            ))),
+        }
+    }
+
+    // Links always stay clickable; widgets are form fields.
+    fn flattens(self, subtype: &str) -> bool {
+        match (self, subtype) {
+            (_, "/Link" | "/Popup") => false,
+            (Self::All, _) => true,
+            (Self::Forms, s) => s == "/Widget",
+            (Self::Annotations, s) => s != "/Widget",
+        }
+    }
+}
+
+// Update hiding (NoView) every visible annotation the mode keeps; `None` when
+// nothing needs hiding. Direct (inline) annotation dictionaries can't be changed
+// on their own, so pages holding them get their annotations moved into new
+// indirect objects first.
+pub(crate) fn flatten_hide_update(
+    json: &Value,
+    mode: FlattenMode,
+) -> Result<Option<Value>, AppError> {
+    let objects = qpdf_json::objects(json)?;
+    let mut next_id = qpdf_json::max_object_id(json)? + 1;
+    let mut hidden = false;
+    let mut updates = Map::new();
+    let mut seen_arrays: HashSet<&str> = HashSet::new();
+    for page in qpdf_json::pages(json)? {
+        let page_ref = qpdf_json::page_ref(page)?;
+        let Some(page_dict) = object_dict(objects, page_ref) else {
+            continue;
+        };
+        let Some(annots_value) = page_dict.get("/Annots") else {
+            continue;
+        };
+        let array_ref = annots_value.as_str();
+        if array_ref.is_some_and(|r| !seen_arrays.insert(r)) {
+            continue;
+        }
+        let Some(annots) = resolve(objects, annots_value).as_array() else {
+            continue;
+        };
+
+        let mut moved = false;
+        let mut new_annots = Vec::with_capacity(annots.len());
+        for annot in annots {
+            let (annot_ref, dict) = match annot {
+                Value::String(r) => match object_dict(objects, r) {
+                    Some(dict) => (r.clone(), dict),
+                    None => {
+                        new_annots.push(annot.clone());
+                        continue;
+                    }
+                },
+                Value::Object(dict) => {
+                    let r = format!("{next_id} 0 R");
+                    next_id += 1;
+                    moved = true;
+                    updates.insert(format!("obj:{r}"), Value::Object(dict.clone()));
+                    (r, dict)
+                }
+                _ => {
+                    new_annots.push(annot.clone());
+                    continue;
+                }
+            };
+            new_annots.push(Value::from(annot_ref.as_str()));
+
+            let subtype = dict.get("/Subtype").and_then(Value::as_str).unwrap_or("");
+            let flags = dict.get("/F").and_then(Value::as_i64).unwrap_or(0);
+            if mode.flattens(subtype) || flags & (FLAG_HIDDEN | FLAG_NO_VIEW) != 0 {
+                continue;
+            }
+            let mut dict = dict.clone();
+            dict.insert("/F".to_string(), Value::from(flags | FLAG_NO_VIEW));
+            dict.insert(SAVED_FLAGS_KEY.to_string(), Value::from(flags));
+            updates.insert(format!("obj:{annot_ref}"), Value::Object(dict));
+            hidden = true;
+        }
+
+        if moved {
+            match array_ref {
+                Some(r) => {
+                    updates.insert(format!("obj:{r}"), Value::from(new_annots));
+                }
+                None => {
+                    let mut page_dict = page_dict.clone();
+                    page_dict.insert("/Annots".to_string(), Value::from(new_annots));
+                    updates.insert(format!("obj:{page_ref}"), Value::Object(page_dict));
+                }
+            }
+        }
+    }
+    // Moving direct annotations only matters when some of them stay.
+    if !hidden {
+        return Ok(None);
+    }
+    qpdf_json::update_document(json, updates).map(Some)
+}
+
+// Update putting back the flags saved by `flatten_hide_update`.
+pub(crate) fn flatten_restore_update(json: &Value) -> Result<Option<Value>, AppError> {
+    let objects = qpdf_json::objects(json)?;
+    let mut updates = Map::new();
+    for (key, object) in objects {
+        let Some(dict) = object.get("value").and_then(Value::as_object) else {
+            continue;
+        };
+        let Some(flags) = dict.get(SAVED_FLAGS_KEY) else {
+            continue;
+        };
+        let mut dict = dict.clone();
+        dict.insert("/F".to_string(), flags.clone());
+        dict.remove(SAVED_FLAGS_KEY);
+        updates.insert(key.clone(), Value::Object(dict));
+    }
+    if updates.is_empty() {
+        return Ok(None);
+    }
+    qpdf_json::update_document(json, updates).map(Some)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum FieldKind {
+    Text,
+    Checkbox,
+    Radio,
+    Combo,
+    List,
+    Button,
+    Signature,
+}
+
+#[derive(Serialize)]
+pub(crate) struct FormField {
+    pub(crate) name: String,
+    #[serde(rename = "type")]
+    pub(crate) kind: FieldKind,
+    pub(crate) value: Value,
+    pub(crate) options: Vec<String>,
+    pub(crate) read_only: bool,
+    pub(crate) required: bool,
+    pub(crate) max_len: Option<usize>,
+    pub(crate) pages: Vec<usize>,
+}
+
+// One terminal field, gathered from qpdf's per-widget "acroform" entries.
+struct FieldInfo {
+    name: String,
+    kind: FieldKind,
+    flags: i64,
+    // Object carrying /T, and so /V.
+    holder: String,
+    value: Value,
+    // /MaxLen of text fields, in characters.
+    max_len: Option<usize>,
+    choices: Vec<String>,
+    // Widget annotation and its "on" appearance states (checkboxes and radios).
+    widgets: Vec<(String, Vec<String>)>,
+    pages: Vec<usize>,
+}
+
+fn form_fields_info(json: &Value) -> Result<Vec<FieldInfo>, AppError> {
+    let objects = qpdf_json::objects(json)?;
+    let entries = json
+        .get("acroform")
+        .and_then(|a| a.get("fields"))
+        .and_then(Value::as_array)
+        .ok_or_else(|| qpdf_json::invalid("missing acroform fields"))?;
+
+    let mut fields: Vec<FieldInfo> = Vec::new();
+    let mut by_name: HashMap<String, usize> = HashMap::new();
+    for entry in entries {
+        let str_of = |key: &str| entry.get(key).and_then(Value::as_str);
+        let bool_of = |key: &str| entry.get(key).and_then(Value::as_bool).unwrap_or(false);
+        let (Some(name), Some(object)) = (str_of("fullname"), str_of("object")) else {
+            continue;
+        };
+        let flags = entry.get("fieldflags").and_then(Value::as_i64).unwrap_or(0);
+        let kind = match str_of("fieldtype") {
+            Some("/Tx") => FieldKind::Text,
+            Some("/Ch") if flags & FIELD_COMBO != 0 => FieldKind::Combo,
+            Some("/Ch") => FieldKind::List,
+            Some("/Sig") => FieldKind::Signature,
+            _ if bool_of("ischeckbox") => FieldKind::Checkbox,
+            _ if bool_of("isradiobutton") => FieldKind::Radio,
+            _ => FieldKind::Button,
+        };
+
+        let idx = *by_name.entry(name.to_string()).or_insert_with(|| {
+            fields.push(FieldInfo {
+                name: name.to_string(),
+                kind,
+                flags,
+                holder: value_holder(objects, object),
+                value: entry.get("value").cloned().unwrap_or(Value::Null),
+                max_len: (kind == FieldKind::Text)
+                    .then(|| max_len(objects, object))
+                    .flatten(),
+                choices: entry
+                    .get("choices")
+                    .and_then(Value::as_array)
+                    .map(|c| {
+                        c.iter()
+                            .filter_map(Value::as_str)
+                            .map(str::to_string)
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+                widgets: Vec::new(),
+                pages: Vec::new(),
+            });
+            fields.len() - 1
+        });
+        let field = &mut fields[idx];
+        if let Some(widget) = entry
+            .get("annotation")
+            .and_then(|a| a.get("object"))
+            .and_then(Value::as_str)
+        {
+            field
+                .widgets
+                .push((widget.to_string(), on_states(objects, widget)));
+        }
+        if let Some(page) = entry.get("pageposfrom1").and_then(Value::as_u64) {
+            let page = page as usize;
+            if !field.pages.contains(&page) {
+                field.pages.push(page);
+            }
+        }
+    }
+    Ok(fields)
+}
+
+// Radio kids and extra widgets usually have no /T of their own; the value lives
+// on the nearest ancestor that has one.
+fn value_holder(objects: &Map<String, Value>, object: &str) -> String {
+    let mut current = object;
+    for _ in 0..64 {
+        let Some(dict) = object_dict(objects, current) else {
+            break;
+        };
+        if dict.contains_key("/T") {
+            return current.to_string();
+        }
+        match dict.get("/Parent").and_then(Value::as_str) {
+            Some(parent) => current = parent,
+            None => break,
+        }
+    }
+    object.to_string()
+}
+
+// /MaxLen is inheritable, so it may sit on any ancestor.
+fn max_len(objects: &Map<String, Value>, object: &str) -> Option<usize> {
+    let mut current = object;
+    for _ in 0..64 {
+        let dict = object_dict(objects, current)?;
+        if let Some(max_len) = dict.get("/MaxLen") {
+            return resolve(objects, max_len)
+                .as_u64()
+                .and_then(|n| usize::try_from(n).ok());
+        }
+        current = dict.get("/Parent").and_then(Value::as_str)?;
+    }
+    None
+}
+
+fn on_states(objects: &Map<String, Value>, widget: &str) -> Vec<String> {
+    object_dict(objects, widget)
+        .and_then(|d| d.get("/AP"))
+        .map(|ap| resolve(objects, ap))
+        .and_then(|ap| ap.get("/N"))
+        .map(|n| resolve(objects, n))
+        .and_then(Value::as_object)
+        .map(|n| n.keys().filter(|k| *k != "/Off").cloned().collect())
+        .unwrap_or_default()
+}
+
+// Lists the fields of `qpdf --json=2 --json-key=acroform --json-key=qpdf` output.
+pub(crate) fn form_fields(json: &Value) -> Result<Vec<FormField>, AppError> {
+    Ok(form_fields_info(json)?
+        .into_iter()
+        .map(|field| {
+            let mut options: Vec<String> = Vec::new();
+            for state in field.widgets.iter().flat_map(|(_, states)| states) {
+                let state = state.trim_start_matches('/').to_string();
+                if !options.contains(&state) {
+                    options.push(state);
+                }
+            }
+            let value = match field.kind {
+                FieldKind::Checkbox => {
+                    Value::Bool(qpdf_json::text(&field.value).is_some_and(|v| v != "Off"))
+                }
+                FieldKind::Radio => qpdf_json::text(&field.value)
+                    .filter(|v| v != "Off")
+                    .map_or(Value::Null, Value::from),
+                _ => match &field.value {
+                    Value::Array(items) => items
+                        .iter()
+                        .filter_map(qpdf_json::text)
+                        .map(Value::from)
+                        .collect(),
+                    v => qpdf_json::text(v).map_or(Value::Null, Value::from),
+                },
+            };
+            FormField {
+                options: match field.kind {
+                    FieldKind::Combo | FieldKind::List => field.choices,
+                    FieldKind::Checkbox | FieldKind::Radio => options,
+                    _ => Vec::new(),
+                },
+                name: field.name,
+                kind: field.kind,
+                value,
+                read_only: field.flags & FIELD_READ_ONLY != 0,
+                required: field.flags & FIELD_REQUIRED != 0,
+                max_len: field.max_len,
+                pages: field.pages,
+            }
+        })
+        .collect())
+}
+
+// Update setting field values (name -> JSON value) and asking for appearances
+// to be regenerated.
+pub(crate) fn fill_update(json: &Value, values: &Map<String, Value>) -> Result<Value, AppError> {
+    let objects = qpdf_json::objects(json)?;
+    let fields = form_fields_info(json)?;
+    if fields.is_empty() {
+        return Err(AppError::Unprocessable(
+            "Document has no form fields".to_string(),
+        ));
+    }
+
+    let mut edits: BTreeMap<String, Map<String, Value>> = BTreeMap::new();
+
+    for (name, value) in values {
+        let Some(field) = fields.iter().find(|f| &f.name == name) else {
+            return Err(AppError::BadRequest(format!("Unknown form field: {name}")));
+        };
+        if field.flags & FIELD_READ_ONLY != 0 {
+            return Err(AppError::BadRequest(format!(
+                "Form field {name} is read-only"
+            )));
+        }
+        let invalid = || AppError::BadRequest(format!("Invalid value for form field {name}"));
+
+        match field.kind {
+            FieldKind::Text => {
+                let text = match value {
+                    Value::String(s) => s.clone(),
+                    Value::Number(n) => n.to_string(),
+                    _ => return Err(invalid()),
+                };
+                // Comb fields draw one character per box, on a single line.
+                if field.flags & FIELD_COMB != 0 && text.contains(['\r', '\n']) {
+                    return Err(invalid());
+                }
+                if let Some(max_len) = field.max_len {
+                    if text.chars().count() > max_len {
+                        return Err(AppError::BadRequest(format!(
+                            "Value for form field {name} is longer than {max_len} characters"
+                        )));
+                    }
+                }
+                edit(&mut edits, objects, &field.holder)?
+                    .insert("/V".to_string(), Value::from(format!("u:{text}")));
+            }
+            FieldKind::Checkbox | FieldKind::Radio => {
+                let states: Vec<&String> = field
+                    .widgets
+                    .iter()
+                    .flat_map(|(_, states)| states)
+                    .collect();
+                // `None` turns the field off.
+                let state = match value {
+                    Value::Bool(false) | Value::Null => None,
+                    Value::Bool(true) if field.kind == FieldKind::Checkbox => {
+                        Some(states.first().map_or("/Yes".to_string(), |s| s.to_string()))
+                    }
+                    Value::String(s) if s == "Off" => None,
+                    Value::String(s) => {
+                        let s = format!("/{s}");
+                        if !states.contains(&&s) {
+                            return Err(invalid());
+                        }
+                        Some(s)
+                    }
+                    _ => return Err(invalid()),
+                };
+                let off = "/Off".to_string();
+                for (widget, widget_states) in &field.widgets {
+                    // A checked checkbox widget shows its own on-state; a radio
+                    // widget is on only for the chosen state.
+                    let appearance = match (field.kind, &state) {
+                        (_, None) => None,
+                        (FieldKind::Checkbox, Some(s)) => widget_states.first().or(Some(s)),
+                        (_, Some(s)) => widget_states.contains(s).then_some(s),
+                    };
+                    edit(&mut edits, objects, widget)?.insert(
+                        "/AS".to_string(),
+                        Value::from(appearance.unwrap_or(&off).as_str()),
+                    );
+                }
+                edit(&mut edits, objects, &field.holder)?.insert(
+                    "/V".to_string(),
+                    Value::from(state.as_ref().unwrap_or(&off).as_str()),
+                );
+            }
+            FieldKind::Combo | FieldKind::List => {
+                let selected: Vec<&str> = match value {
+                    Value::String(s) => vec![s.as_str()],
+                    Value::Array(items) if field.flags & FIELD_MULTI_SELECT != 0 => items
+                        .iter()
+                        .map(Value::as_str)
+                        .collect::<Option<_>>()
+                        .ok_or_else(invalid)?,
+                    _ => return Err(invalid()),
+                };
+                let free_text = field.kind == FieldKind::Combo && field.flags & FIELD_EDIT != 0;
+                if !free_text
+                    && selected
+                        .iter()
+                        .any(|s| !field.choices.iter().any(|c| c == s))
+                {
+                    return Err(invalid());
+                }
+                let encoded: Vec<Value> = selected
+                    .iter()
+                    .map(|s| Value::from(format!("u:{s}")))
+                    .collect();
+                let holder = edit(&mut edits, objects, &field.holder)?;
+                holder.insert(
+                    "/V".to_string(),
+                    match <[Value; 1]>::try_from(encoded) {
+                        Ok([single]) => single,
+                        Err(many) => Value::from(many),
+                    },
+                );
+                // Selected indices would contradict the new value.
+                holder.remove("/I");
+            }
+            FieldKind::Button | FieldKind::Signature => {
+                return Err(AppError::BadRequest(format!(
+                    "Form field {name} cannot be filled"
+                )));
+            }
+        }
+    }
+
+    // qpdf only generates appearances when the form says they are stale.
+    let root = qpdf_json::trailer(objects)
+        .and_then(|t| t.get("/Root"))
+        .and_then(Value::as_str)
+        .ok_or_else(|| qpdf_json::invalid("missing document root"))?;
+    let acroform = object_dict(objects, root)
+        .and_then(|r| r.get("/AcroForm"))
+        .ok_or_else(|| qpdf_json::invalid("missing AcroForm"))?;
+    match acroform.as_str() {
+        Some(acroform_ref) => {
+            edit(&mut edits, objects, acroform_ref)?
+                .insert("/NeedAppearances".to_string(), Value::Bool(true));
+        }
+        None => {
+            let mut acroform = acroform.clone();
+            if let Some(dict) = acroform.as_object_mut() {
+                dict.insert("/NeedAppearances".to_string(), Value::Bool(true));
+            }
+            edit(&mut edits, objects, root)?.insert("/AcroForm".to_string(), acroform);
+        }
+    }
+
+    let updates = edits
+        .into_iter()
+        .map(|(obj_ref, dict)| (format!("obj:{obj_ref}"), Value::Object(dict)))
+        .collect();
+    qpdf_json::update_document(json, updates)
+}
+
+// Pending copy of an object, taken from the document on first use.
+fn edit<'a>(
+    edits: &'a mut BTreeMap<String, Map<String, Value>>,
+    objects: &Map<String, Value>,
+    obj_ref: &str,
+) -> Result<&'a mut Map<String, Value>, AppError> {
+    if !edits.contains_key(obj_ref) {
+        let dict = object_dict(objects, obj_ref)
+            .cloned()
+            .ok_or_else(|| qpdf_json::invalid("missing field object"))?;
+        edits.insert(obj_ref.to_string(), dict);
+    }
+    Ok(edits.get_mut(obj_ref).expect("inserted above"))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    // A one-page form: a required text field limited to 5 characters, a
+    // checkbox and a combo box, plus a link and a direct text annotation.
+    fn form() -> Value {
+        json!({
+            "qpdf": [
+                {"jsonversion": 2, "pdfversion": "1.7", "maxobjectid": 9},
+                {
+                    "obj:1 0 R": {"value": {
+                        "/Type": "/Catalog",
+                        "/Pages": "2 0 R",
+                        "/AcroForm": {"/Fields": ["4 0 R", "5 0 R", "6 0 R"]}
+                    }},
+                    "obj:2 0 R": {"value": {"/Type": "/Pages", "/Kids": ["3 0 R"], "/Count": 1}},
+                    "obj:3 0 R": {"value": {
+                        "/Type": "/Page",
+                        "/Parent": "2 0 R",
+                        "/MediaBox": [0, 0, 612, 792],
+                        "/Annots": [
+                            "4 0 R",
+                            "5 0 R",
+                            "6 0 R",
+                            "7 0 R",
+                            {"/Subtype": "/Text", "/Rect": [0, 0, 20, 20]}
+                        ]
+                    }},
+                    "obj:4 0 R": {"value": {
+                        "/FT": "/Tx", "/T": "u:name", "/V": "u:Ada", "/Ff": 2, "/MaxLen": 5,
+                        "/Subtype": "/Widget"
+                    }},
+                    "obj:5 0 R": {"value": {
+                        "/FT": "/Btn", "/T": "u:agree", "/V": "/Off", "/AS": "/Off",
+                        "/Subtype": "/Widget",
+                        "/AP": {"/N": {"/Yes": "8 0 R", "/Off": "9 0 R"}}
+                    }},
+                    "obj:6 0 R": {"value": {
+                        "/FT": "/Ch", "/T": "u:country", "/V": "u:DE", "/Ff": 131072,
+                        "/Opt": ["u:DE", "u:FR"], "/I": [0], "/Subtype": "/Widget"
+                    }},
+                    "obj:7 0 R": {"value": {"/Subtype": "/Link", "/F": 4}},
+                    "obj:8 0 R": {"stream": {"dict": {}}},
+                    "obj:9 0 R": {"stream": {"dict": {}}},
+                    "trailer": {"value": {"/Root": "1 0 R"}}
+                }
+            ],
+            "pages": [{"object": "3 0 R"}],
+            "acroform": {"fields": [
+                {
+                    "fullname": "name", "object": "4 0 R", "fieldtype": "/Tx",
+                    "fieldflags": 2, "value": "u:Ada",
+                    "annotation": {"object": "4 0 R"}, "pageposfrom1": 1
+                },
+                {
+                    "fullname": "agree", "object": "5 0 R", "fieldtype": "/Btn",
+                    "fieldflags": 0, "ischeckbox": true, "value": "/Off",
+                    "annotation": {"object": "5 0 R"}, "pageposfrom1": 1
+                },
+                {
+                    "fullname": "country", "object": "6 0 R", "fieldtype": "/Ch",
+                    "fieldflags": 131072, "choices": ["DE", "FR"], "value": "u:DE",
+                    "annotation": {"object": "6 0 R"}, "pageposfrom1": 1
+                }
+            ]}
+        })
+    }
+
+    fn updated<'a>(update: &'a Value, obj_ref: &str) -> &'a Map<String, Value> {
+        update["qpdf"][1][format!("obj:{obj_ref}")]["value"]
+            .as_object()
+            .unwrap_or_else(|| panic!("{obj_ref} not in update"))
+    }
+
+    // What `--update-from-json` would leave behind.
+    fn apply(json: &Value, update: &Value) -> Value {
+        let mut json = json.clone();
+        for (key, object) in update["qpdf"][1].as_object().unwrap() {
+            json["qpdf"][1][key] = object.clone();
+        }
+        json
+    }
+
+    #[test]
+    fn lists_fields() {
+        let fields = form_fields(&form()).unwrap();
+        let summary: Vec<_> = fields
+            .iter()
+            .map(|f| (f.name.as_str(), f.kind, f.value.clone(), f.options.clone()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("name", FieldKind::Text, json!("Ada"), vec![]),
+                (
+                    "agree",
+                    FieldKind::Checkbox,
+                    json!(false),
+                    vec!["Yes".to_string()]
+                ),
+                (
+                    "country",
+                    FieldKind::Combo,
+                    json!("DE"),
+                    vec!["DE".to_string(), "FR".to_string()]
+                ),
+            ]
+        );
+        assert!(fields[0].required && !fields[0].read_only);
+        assert_eq!(fields[0].max_len, Some(5));
+        assert_eq!(fields[1].max_len, None);
+        assert_eq!(fields[2].pages, vec![1]);
+    }
+
+    #[test]
+    fn fill_sets_values_and_appearance_states() {
+        let values = json!({"name": "Bob", "agree": true, "country": "FR"});
+        let update = fill_update(&form(), values.as_object().unwrap()).unwrap();
+
+        assert_eq!(updated(&update, "4 0 R")["/V"], json!("u:Bob"));
+        let checkbox = updated(&update, "5 0 R");
+        assert_eq!(checkbox["/V"], json!("/Yes"));
+        assert_eq!(checkbox["/AS"], json!("/Yes"));
+        let combo = updated(&update, "6 0 R");
+        assert_eq!(combo["/V"], json!("u:FR"));
+        assert!(!combo.contains_key("/I"));
+        // The AcroForm is direct, so it is rewritten inside the catalog.
+        assert_eq!(
+            updated(&update, "1 0 R")["/AcroForm"]["/NeedAppearances"],
+            json!(true)
+        );
+    }
+
+    #[test]
+    fn fill_rejects_invalid_values() {
+        for values in [
+            json!({"name": "Adalbert"}),
+            json!({"country": "IT"}),
+            json!({"agree": "Maybe"}),
+            json!({"missing": "x"}),
+        ] {
+            assert!(
+                matches!(
+                    fill_update(&form(), values.as_object().unwrap()),
+                    Err(AppError::BadRequest(_))
+                ),
+                "{values} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn fill_rejects_line_breaks_in_comb_fields() {
+        let mut json = form();
+        json["acroform"]["fields"][0]["fieldflags"] = json!(FIELD_COMB);
+        let values = json!({"name": "A\nB"});
+        assert!(matches!(
+            fill_update(&json, values.as_object().unwrap()),
+            Err(AppError::BadRequest(_))
+        ));
+    }
+
+    #[test]
+    fn flatten_hides_kept_annotations_and_moves_direct_ones() {
+        let update = flatten_hide_update(&form(), FlattenMode::Forms)
+            .unwrap()
+            .expect("link and text annotation stay");
+
+        // Widgets are flattened, so they stay visible.
+        for widget in ["4 0 R", "5 0 R", "6 0 R"] {
+            assert!(update["qpdf"][1].get(format!("obj:{widget}")).is_none());
+        }
+        let link = updated(&update, "7 0 R");
+        assert_eq!(link["/F"], json!(4 | FLAG_NO_VIEW));
+        assert_eq!(link[SAVED_FLAGS_KEY], json!(4));
+        // The direct annotation gets the first free object number.
+        assert_eq!(updated(&update, "10 0 R")["/F"], json!(FLAG_NO_VIEW));
+        assert_eq!(
+            updated(&update, "3 0 R")["/Annots"],
+            json!(["4 0 R", "5 0 R", "6 0 R", "7 0 R", "10 0 R"])
+        );
+    }
+
+    #[test]
+    fn flatten_restore_puts_original_flags_back() {
+        let json = form();
+        assert!(flatten_restore_update(&json).unwrap().is_none());
+
+        let hide = flatten_hide_update(&json, FlattenMode::All)
+            .unwrap()
+            .unwrap();
+        let hidden = apply(&json, &hide);
+        let restore = flatten_restore_update(&hidden).unwrap().unwrap();
+        let link = updated(&restore, "7 0 R");
+        assert_eq!(link["/F"], json!(4));
+        assert!(!link.contains_key(SAVED_FLAGS_KEY));
+    }
+}
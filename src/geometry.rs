@@ -0,0 +1,538 @@
+use std::fmt::Write;
+
+use serde_json::{Map, Value};
+
+use crate::error::AppError;
+use crate::qpdf_json::{self, object_dict, resolve};
+use crate::util::paper_size_points;
+
+pub(crate) struct PageGeometry {
+    pub(crate) media_box: [f64; 4],
+    pub(crate) crop_box: Option<[f64; 4]>,
+    pub(crate) rotate: i64,
+}
+
+impl PageGeometry {
+    // Box a viewer shows: the CropBox when set, otherwise the MediaBox.
+    pub(crate) fn visible_box(&self) -> [f64; 4] {
+        self.crop_box.unwrap_or(self.media_box)
+    }
+
+    // Width and height of the visible box as displayed, i.e. after /Rotate.
+    pub(crate) fn size(&self) -> (f64, f64) {
+        self.size_with_rotate(self.rotate)
+    }
+
+    pub(crate) fn size_with_rotate(&self, rotate: i64) -> (f64, f64) {
+        let [llx, lly, urx, ury] = self.visible_box();
+        let (w, h) = (urx - llx, ury - lly);
+        if rotate.rem_euclid(180) == 0 {
+            (w, h)
+        } else {
+            (h, w)
+        }
+    }
+}
+
+// Reads every page's boxes from `qpdf --json=2 --json-key=pages --json-key=qpdf`
+// output, following /Parent for inherited attributes.
+pub(crate) fn page_geometry_from_qpdf_json(json: &Value) -> Result<Vec<PageGeometry>, AppError> {
+    let objects = qpdf_json::objects(json)?;
+    let pages = qpdf_json::pages(json)?;
+
+    let mut out = Vec::with_capacity(pages.len());
+    for page in pages {
+        let page_ref = qpdf_json::page_ref(page)?;
+        let media_box = inherited(objects, page_ref, "/MediaBox")
+            .and_then(|v| pdf_box(objects, v))
+            .unwrap_or([0.0, 0.0, 612.0, 792.0]);
+        let crop_box = inherited(objects, page_ref, "/CropBox").and_then(|v| pdf_box(objects, v));
+        let rotate = inherited(objects, page_ref, "/Rotate")
+            .and_then(Value::as_i64)
+            .unwrap_or(0)
+            .rem_euclid(360);
+        out.push(PageGeometry {
+            media_box,
+            crop_box,
+            rotate,
+        });
+    }
+    Ok(out)
+}
+
+pub(crate) fn inherited<'a>(
+    objects: &'a Map<String, Value>,
+    page_ref: &str,
+    key: &str,
+) -> Option<&'a Value> {
+    let mut dict = object_dict(objects, page_ref)?;
+    // Bounded walk up the page tree; a cycle would otherwise loop forever.
+    for _ in 0..64 {
+        if let Some(v) = dict.get(key) {
+            return Some(resolve(objects, v));
+        }
+        let parent = dict.get("/Parent")?.as_str()?;
+        dict = object_dict(objects, parent)?;
+    }
+    None
+}
+
+fn pdf_box(objects: &Map<String, Value>, value: &Value) -> Option<[f64; 4]> {
+    let items = value.as_array()?;
+    if items.len() != 4 {
+        return None;
+    }
+    let mut n = [0.0; 4];
+    for (slot, item) in n.iter_mut().zip(items) {
+        *slot = resolve(objects, item).as_f64()?;
+    }
+    Some([
+        n[0].min(n[2]),
+        n[1].min(n[3]),
+        n[0].max(n[2]),
+        n[1].max(n[3]),
+    ])
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum PageFit {
+    Fit,
+    Fill,
+    CenterNoScale,
+}
+
+impl PageFit {
+    pub(crate) fn parse(s: &str) -> Result<Self, AppError> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "fit" => Ok(Self::Fit),
+            "fill" => Ok(Self::Fill),
+            "center-no-scale" => Ok(Self::CenterNoScale),
+            other => Err(AppError::BadRequest(format!(
+                "Invalid page fit: {other} (expected fit, fill or center-no-scale)"
+            ))),
+        }
+    }
+}
+
+pub(crate) struct PageSizeOptions {
+    pub(crate) width: f64,
+    pub(crate) height: f64,
+    pub(crate) fit: PageFit,
+}
+
+impl PageSizeOptions {
+    pub(crate) fn parse(size: &str, fit: Option<&str>) -> Result<Self, AppError> {
+        let (width, height) = paper_size_points(size).ok_or_else(|| {
+            AppError::BadRequest(format!(
+                "Invalid page size: {size} (expected a3, a4, a5, letter, legal, tabloid or WxH in points)"
+            ))
+        })?;
+        let fit = match fit {
+            Some(v) => PageFit::parse(v)?,
+            None => PageFit::Fit,
+        };
+        Ok(Self { width, height, fit })
+    }
+
+    // BeginPage hook placing each page on the fixed media, used for `fill` and
+    // `center-no-scale` (`fit` is Ghostscript's own -dPDFFitPage). The page
+    // counter is bumped in EndPage because BeginPage also runs on every
+    // setpagedevice the PDF interpreter issues.
+    pub(crate) fn to_postscript(&self, pages: &[PageGeometry]) -> String {
+        let mut transforms = String::new();
+        for page in pages {
+            let (w, h) = page.size();
+            let (w, h) = (w.max(1.0), h.max(1.0));
+            let scale = match self.fit {
+                PageFit::Fit => (self.width / w).min(self.height / h),
+                PageFit::Fill => (self.width / w).max(self.height / h),
+                PageFit::CenterNoScale => 1.0,
+            };
+            let tx = (self.width - w * scale) / 2.0;
+            let ty = (self.height - h * scale) / 2.0;
+            let _ = writeln!(transforms, "[{scale:.6} {tx:.4} {ty:.4}]");
+        }
+
+        format!(
+            "%!PS\n\
+             /PTPlacement [\n{transforms}] def\n\
+             currentglobal true setglobal globaldict /PTPageNo 0 put setglobal\n\
+             << /BeginPage {{\n\
+             pop globaldict /PTPageNo get dup userdict /PTPlacement get length lt\n\
+             {{ userdict /PTPlacement get exch get aload pop translate dup scale }} {{ pop }} ifelse\n\
+             }} bind\n\
+             /EndPage {{\n\
+             exch pop 0 eq dup {{ globaldict /PTPageNo 2 copy get 1 add put }} if\n\
+             }} bind >> setpagedevice\n"
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum CropUnit {
+    #[default]
+    Pt,
+    Percent,
+}
+
+// Margins to trim from each side as the page is displayed, or `auto` to crop to
+// the content bounding box (plus `padding` points).
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct CropSpec {
+    #[serde(default)]
+    pub(crate) top: f64,
+    #[serde(default)]
+    pub(crate) right: f64,
+    #[serde(default)]
+    pub(crate) bottom: f64,
+    #[serde(default)]
+    pub(crate) left: f64,
+    #[serde(default)]
+    pub(crate) unit: CropUnit,
+    #[serde(default)]
+    pub(crate) auto: bool,
+    #[serde(default)]
+    pub(crate) padding: f64,
+}
+
+impl CropSpec {
+    pub(crate) fn parse(json: &str) -> Result<Self, AppError> {
+        let spec: Self = serde_json::from_str(json)
+            .map_err(|e| AppError::BadRequest(format!("Invalid crop: {e}")))?;
+        spec.validate()?;
+        Ok(spec)
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), AppError> {
+        let margins = [self.top, self.right, self.bottom, self.left];
+        if margins.iter().any(|m| !m.is_finite() || *m < 0.0) {
+            return Err(AppError::BadRequest(
+                "Crop margins must be non-negative numbers".to_string(),
+            ));
+        }
+        if self.auto {
+            if margins.iter().any(|m| *m > 0.0) {
+                return Err(AppError::BadRequest(
+                    "Crop margins cannot be combined with auto".to_string(),
+                ));
+            }
+            if !(0.0..=144.0).contains(&self.padding) {
+                return Err(AppError::BadRequest(
+                    "Crop padding must be between 0 and 144".to_string(),
+                ));
+            }
+            return Ok(());
+        }
+        if self.padding != 0.0 {
+            return Err(AppError::BadRequest(
+                "Crop padding requires auto".to_string(),
+            ));
+        }
+        if self.unit == CropUnit::Percent
+            && (self.top + self.bottom >= 100.0 || self.left + self.right >= 100.0)
+        {
+            return Err(AppError::BadRequest(
+                "Crop percentages leave nothing of the page".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    // New CropBox in the page's own (unrotated) space. `content` is the content
+    // bounding box in displayed space, needed for `auto`; auto-crop of a blank
+    // page leaves it alone.
+    pub(crate) fn crop_box(
+        &self,
+        page: &PageGeometry,
+        content: Option<[f64; 4]>,
+    ) -> Result<Option<[f64; 4]>, AppError> {
+        let (w, h) = page.size();
+        // top, right, bottom, left as displayed
+        let displayed = if self.auto {
+            let Some([x0, y0, x1, y1]) = content else {
+                return Ok(None);
+            };
+            let p = self.padding;
+            [
+                (h - y1 - p).max(0.0),
+                (w - x1 - p).max(0.0),
+                (y0 - p).max(0.0),
+                (x0 - p).max(0.0),
+            ]
+        } else {
+            match self.unit {
+                CropUnit::Pt => [self.top, self.right, self.bottom, self.left],
+                CropUnit::Percent => [
+                    self.top * h / 100.0,
+                    self.right * w / 100.0,
+                    self.bottom * h / 100.0,
+                    self.left * w / 100.0,
+                ],
+            }
+        };
+
+        // /Rotate turns the page clockwise, so each quarter turn moves the
+        // displayed sides one step around the unrotated box.
+        let turns = (page.rotate / 90) as usize % 4;
+        let [top, right, bottom, left]: [f64; 4] =
+            std::array::from_fn(|i| displayed[(i + turns) % 4]);
+        let [llx, lly, urx, ury] = page.visible_box();
+        let cropped = [llx + left, lly + bottom, urx - right, ury - top];
+        if cropped[2] - cropped[0] < 1.0 || cropped[3] - cropped[1] < 1.0 {
+            if self.auto {
+                return Ok(None);
+            }
+            return Err(AppError::BadRequest(
+                "Crop margins are larger than the page".to_string(),
+            ));
+        }
+        Ok(Some(cropped))
+    }
+}
+
+// Builds a `qpdf --update-from-json` document setting CropBox and TrimBox on the
+// given pages (0-based index into the `pages` array of `json`). The two boxes
+// must stay equal: OCR renders the CropBox and qpdf fits the text layer into
+// the TrimBox.
+pub(crate) fn crop_update_json(
+    json: &Value,
+    boxes: &[(usize, [f64; 4])],
+) -> Result<Value, AppError> {
+    let objects = qpdf_json::objects(json)?;
+    let pages = qpdf_json::pages(json)?;
+
+    let mut updates = Map::new();
+    for (idx, crop) in boxes {
+        let page = pages
+            .get(*idx)
+            .ok_or_else(|| qpdf_json::invalid("missing page"))?;
+        let page_ref = qpdf_json::page_ref(page)?;
+        let mut dict = object_dict(objects, page_ref)
+            .cloned()
+            .ok_or_else(|| qpdf_json::invalid("missing page object"))?;
+        let crop = Value::from(crop.to_vec());
+        dict.insert("/CropBox".to_string(), crop.clone());
+        dict.insert("/TrimBox".to_string(), crop);
+        updates.insert(format!("obj:{page_ref}"), Value::Object(dict));
+    }
+    qpdf_json::update_document(json, updates)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn page(rotate: i64) -> PageGeometry {
+        PageGeometry {
+            media_box: [0.0, 0.0, 600.0, 800.0],
+            crop_box: None,
+            rotate,
+        }
+    }
+
+    fn margins(top: f64, right: f64, bottom: f64, left: f64, unit: CropUnit) -> CropSpec {
+        CropSpec {
+            top,
+            right,
+            bottom,
+            left,
+            unit,
+            auto: false,
+            padding: 0.0,
+        }
+    }
+
+    #[test]
+    fn crop_margins_follow_the_displayed_sides() {
+        let spec = margins(10.0, 20.0, 30.0, 40.0, CropUnit::Pt);
+        let cases = [
+            (0, [40.0, 30.0, 580.0, 790.0]),
+            // Turned clockwise: the displayed top is the unrotated left side.
+            (90, [10.0, 40.0, 570.0, 780.0]),
+            (180, [20.0, 10.0, 560.0, 770.0]),
+            (270, [30.0, 20.0, 590.0, 760.0]),
+        ];
+        for (rotate, expected) in cases {
+            assert_eq!(
+                spec.crop_box(&page(rotate), None).unwrap(),
+                Some(expected),
+                "rotate {rotate}"
+            );
+        }
+    }
+
+    #[test]
+    fn crop_percentages_use_the_displayed_size() {
+        // Displayed 800 wide and 600 high, so 10% off the top is 60pt.
+        let spec = margins(10.0, 0.0, 0.0, 5.0, CropUnit::Percent);
+        assert_eq!(
+            spec.crop_box(&page(90), None).unwrap(),
+            Some([60.0, 40.0, 600.0, 800.0])
+        );
+    }
+
+    #[test]
+    fn crop_starts_from_an_existing_crop_box() {
+        let page = PageGeometry {
+            crop_box: Some([50.0, 50.0, 550.0, 750.0]),
+            ..page(0)
+        };
+        let spec = margins(0.0, 0.0, 0.0, 10.0, CropUnit::Pt);
+        assert_eq!(
+            spec.crop_box(&page, None).unwrap(),
+            Some([60.0, 50.0, 550.0, 750.0])
+        );
+    }
+
+    #[test]
+    fn auto_crop_pads_the_content_box() {
+        let spec = CropSpec {
+            auto: true,
+            padding: 6.0,
+            ..margins(0.0, 0.0, 0.0, 0.0, CropUnit::Pt)
+        };
+        assert_eq!(
+            spec.crop_box(&page(0), Some([100.0, 100.0, 500.0, 700.0]))
+                .unwrap(),
+            Some([94.0, 94.0, 506.0, 706.0])
+        );
+        // A blank page has no content box and stays as it is.
+        assert_eq!(spec.crop_box(&page(0), None).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_invalid_crops() {
+        for json in [
+            r#"{"top":-1}"#,
+            r#"{"top":60,"bottom":40,"unit":"percent"}"#,
+            r#"{"auto":true,"top":5}"#,
+            r#"{"padding":5}"#,
+            r#"{"auto":true,"padding":200}"#,
+            r#"{"inset":5}"#,
+        ] {
+            assert!(
+                matches!(CropSpec::parse(json), Err(AppError::BadRequest(_))),
+                "{json} should be rejected"
+            );
+        }
+        let spec = margins(500.0, 0.0, 400.0, 0.0, CropUnit::Pt);
+        assert!(matches!(
+            spec.crop_box(&page(0), None),
+            Err(AppError::BadRequest(_))
+        ));
+    }
+
+    #[test]
+    fn crop_update_sets_matching_crop_and_trim_boxes() {
+        let json = json!({
+            "qpdf": [
+                {"jsonversion": 2, "pdfversion": "1.7", "maxobjectid": 3},
+                {
+                    "obj:3 0 R": {"value": {"/Type": "/Page", "/MediaBox": [0, 0, 612, 792]}}
+                }
+            ],
+            "pages": [{"object": "3 0 R"}]
+        });
+        let update = crop_update_json(&json, &[(0, [10.0, 20.0, 600.0, 700.0])]).unwrap();
+        let page = &update["qpdf"][1]["obj:3 0 R"]["value"];
+        assert_eq!(page["/CropBox"], json!([10.0, 20.0, 600.0, 700.0]));
+        // OCR renders the CropBox; qpdf fits the text layer into the TrimBox.
+        assert_eq!(page["/TrimBox"], page["/CropBox"]);
+        assert_eq!(page["/MediaBox"], json!([0, 0, 612, 792]));
+    }
+
+    #[test]
+    fn reads_inherited_and_indirect_page_boxes() {
+        let json = json!({
+            "qpdf": [
+                {"jsonversion": 2, "pdfversion": "1.7", "maxobjectid": 6},
+                {
+                    "obj:2 0 R": {"value": {
+                        "/Type": "/Pages", "/Kids": ["3 0 R", "4 0 R", "5 0 R"], "/Count": 3,
+                        "/MediaBox": [0, 0, 595, 842], "/Rotate": 90
+                    }},
+                    "obj:3 0 R": {"value": {"/Type": "/Page", "/Parent": "2 0 R"}},
+                    "obj:4 0 R": {"value": {
+                        "/Type": "/Page", "/Parent": "2 0 R", "/MediaBox": "6 0 R",
+                        "/CropBox": [10, 20, 110, 220], "/Rotate": -90
+                    }},
+                    "obj:5 0 R": {"value": {"/Type": "/Page"}},
+                    "obj:6 0 R": {"value": [612, 792, 0, 0]}
+                }
+            ],
+            "pages": [{"object": "3 0 R"}, {"object": "4 0 R"}, {"object": "5 0 R"}]
+        });
+        let pages = page_geometry_from_qpdf_json(&json).unwrap();
+        assert_eq!(pages.len(), 3);
+
+        assert_eq!(pages[0].media_box, [0.0, 0.0, 595.0, 842.0]);
+        assert_eq!(pages[0].rotate, 90);
+        assert_eq!(pages[0].size(), (842.0, 595.0));
+
+        assert_eq!(pages[1].media_box, [0.0, 0.0, 612.0, 792.0]);
+        assert_eq!(pages[1].rotate, 270);
+        assert_eq!(pages[1].size(), (200.0, 100.0));
+
+        assert_eq!(pages[2].media_box, [0.0, 0.0, 612.0, 792.0]);
+        assert_eq!(pages[2].rotate, 0);
+    }
+
+    #[test]
+    fn parses_page_size_options() {
+        let options = PageSizeOptions::parse("A4", None).unwrap();
+        assert_eq!((options.width, options.height), (595.0, 842.0));
+        assert_eq!(options.fit, PageFit::Fit);
+
+        let options = PageSizeOptions::parse("300 x 200", Some("Center-No-Scale")).unwrap();
+        assert_eq!((options.width, options.height), (300.0, 200.0));
+        assert_eq!(options.fit, PageFit::CenterNoScale);
+
+        for (size, fit) in [("b5", None), ("10x10", None), ("a4", Some("stretch"))] {
+            assert!(
+                matches!(
+                    PageSizeOptions::parse(size, fit),
+                    Err(AppError::BadRequest(_))
+                ),
+                "{size:?} {fit:?} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn page_placement_scales_and_centres_the_displayed_page() {
+        // A 200x100 landscape page, once upright and once as a rotated portrait
+        // MediaBox, on a 400x400 sheet.
+        let pages = [
+            PageGeometry {
+                media_box: [0.0, 0.0, 200.0, 100.0],
+                crop_box: None,
+                rotate: 0,
+            },
+            PageGeometry {
+                media_box: [0.0, 0.0, 100.0, 200.0],
+                crop_box: None,
+                rotate: 90,
+            },
+        ];
+        for (fit, transform) in [
+            (PageFit::Fit, "[2.000000 0.0000 100.0000]"),
+            (PageFit::Fill, "[4.000000 -200.0000 0.0000]"),
+            (PageFit::CenterNoScale, "[1.000000 100.0000 150.0000]"),
+        ] {
+            let options = PageSizeOptions {
+                width: 400.0,
+                height: 400.0,
+                fit,
+            };
+            let ps = options.to_postscript(&pages);
+            assert!(
+                ps.contains(&format!("/PTPlacement [\n{transform}\n{transform}\n] def")),
+                "{fit:?}: {ps}"
+            );
+        }
+    }
+}
@@ -1,13 +1,16 @@
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use axum::body::Body;
-use axum::extract::multipart::MultipartRejection;
+use axum::extract::multipart::{Field, MultipartRejection};
 use axum::extract::{Multipart, State};
 use axum::http::{header, HeaderValue};
 use axum::response::IntoResponse;
 use axum::response::Response;
 use axum::Json;
+use base64::Engine;
 use bytes::Bytes;
 use serde::Serialize;
 use tempfile::TempDir;
@@ -17,12 +20,40 @@ use tokio_stream::wrappers::ReceiverStream;
 use tower_cookies::Cookies;
 use tracing::{error, info};
 
-use crate::constants::{MAX_FILE_BYTES, MAX_PDFS};
+use crate::constants::{MAX_FILE_BYTES, MAX_PDFS, MAX_RENDER_PAGES, MAX_SPLIT_PARTS};
 use crate::error::AppError;
-use crate::pdf::{looks_like_pdf, write_multipart_field_to_file, MergePageRef};
+use crate::forms::FlattenMode;
+use crate::geometry::{CropSpec, PageGeometry, PageSizeOptions};
+use crate::impose::ImpositionOptions;
+use crate::inspect::{EncryptionInfo, LockedInspectReport};
+use crate::metadata::DocumentMetadata;
+use crate::outline::{move_outline_pages, remap_outline, OutlineEntry};
+use crate::pdf::{
+    image_render_options, looks_like_image, looks_like_pdf, parse_layout_page_size,
+    parse_ocr_language, qpdf_check_encryption_with_timeout, qpdf_decrypt_file_with_timeout,
+    repair_pdf_with_timeout, text_render_options, write_multipart_field_to_file, ColorMode,
+    EncryptionOptions, ImageFormat, ImageToPdfOptions, InputEncryption, LayoutEntry,
+    OutputPermissions, OutputProfile, PdfOutputOptions, RenderOptions, RepairStrategy,
+};
+use crate::ranges::{every_n_pages, parse_page_ranges, PageRange};
+use crate::stamp::{StampPlan, StampingOptions, WatermarkOptions};
 use crate::state::AppState;
 use crate::util::parse_bool_loose;
 
+// A layout entry dropped by blank-page removal.
+#[derive(Serialize)]
+pub(crate) struct RemovedPage {
+    pub(crate) doc: String,
+    pub(crate) page: usize,
+}
+
+// A merge input that had to be repaired before processing.
+#[derive(Serialize)]
+pub(crate) struct RepairedInput {
+    pub(crate) file: String,
+    pub(crate) strategy: RepairStrategy,
+}
+
 #[derive(Serialize)]
 pub(crate) struct NPagesResponse {
     pub(crate) pages: usize,
@@ -35,69 +66,115 @@ pub(crate) async fn npages(
 ) -> Result<Response, AppError> {
     let _username = state.require_auth(&cookies)?;
 
-    let mut multipart = multipart.map_err(|e| {
-        error!(error = %e, "multipart parse failed");
-        AppError::BadRequest("Error parsing multipart/form-data request".to_string())
-    })?;
+    let mut multipart = multipart_or_bad_request(multipart)?;
 
     let tmp = TempDir::new().map_err(|e| AppError::Internal(e.to_string()))?;
     let mut pdf_path: Option<PathBuf> = None;
     let mut file_name: Option<String> = None;
+    let mut password: Option<String> = None;
 
+    // Unlike the other single-file endpoints, npages has always ignored extra
+    // `file` parts and unknown fields; the web UI relies on that.
     while let Some(mut field) = multipart
         .next_field()
         .await
         .map_err(|e| AppError::BadRequest(e.to_string()))?
     {
         let name = field.name().unwrap_or("").to_string();
-        if name != "file" {
+        if name == "password" {
+            password = Some(field_text(field).await?);
+            continue;
+        }
+        if name != "file" || pdf_path.is_some() {
             continue;
         }
 
-        let content_type = field
-            .content_type()
-            .map(|m| m.split(';').next().unwrap_or("").trim().to_string())
-            .unwrap_or_default();
         let f_name = field.file_name().unwrap_or("file.pdf").to_string();
-
-        if !content_type.is_empty() && content_type != mime::APPLICATION_PDF.essence_str() {
-            return Err(AppError::BadRequest(format!(
-                "Only PDF files are allowed (got {content_type} for {f_name})"
-            )));
-        }
+        ensure_upload_content_type(&field, &f_name, true)?;
 
         let path = tmp.path().join("in.pdf");
-        let written = write_multipart_field_to_file(&mut field, &path).await?;
-        if written > MAX_FILE_BYTES {
-            return Err(AppError::BadRequest(format!(
-                "{f_name} is too large (max {} MB)",
-                MAX_FILE_BYTES / 1024 / 1024
-            )));
-        }
-        if !looks_like_pdf(&path).await? {
-            return Err(AppError::BadRequest(format!(
-                "{f_name} does not look like a PDF"
-            )));
-        }
+        let kind = save_upload_field(&mut field, &path, &f_name, true).await?;
+        let path = match kind {
+            UploadKind::Pdf => path,
+            UploadKind::Image => {
+                crate::pdf::img2pdf_convert_with_timeout(
+                    &tmp,
+                    &path,
+                    &f_name,
+                    &ImageToPdfOptions::default(),
+                    state.process_timeout,
+                )
+                .await?
+            }
+        };
 
         pdf_path = Some(path);
         file_name = Some(f_name);
-        break;
     }
 
     let Some(path) = pdf_path else {
         return Err(AppError::BadRequest("Missing file".to_string()));
     };
+    let file_name = file_name.unwrap_or_else(|| "file.pdf".to_string());
+
+    let path = unlock_input(
+        &tmp,
+        path,
+        password.as_deref(),
+        &file_name,
+        Some("password"),
+        state.process_timeout,
+    )
+    .await?;
 
     let pages = crate::pdf::qpdf_show_npages_with_timeout(&path, state.process_timeout).await?;
-    info!(
-        pages,
-        file = %file_name.unwrap_or_else(|| "file.pdf".to_string()),
-        "computed page count"
-    );
+    info!(pages, file = %file_name, "computed page count");
     Ok(Json(NPagesResponse { pages }).into_response())
 }
 
+pub(crate) async fn inspect(
+    State(state): State<AppState>,
+    cookies: Cookies,
+    multipart: Result<Multipart, MultipartRejection>,
+) -> Result<Response, AppError> {
+    let _username = state.require_auth(&cookies)?;
+
+    let mut multipart = multipart_or_bad_request(multipart)?;
+
+    let tmp = TempDir::new().map_err(|e| AppError::Internal(e.to_string()))?;
+    // qpdf reads encrypted files itself, so the upload is inspected as is.
+    let upload =
+        read_single_upload_encrypted(&mut multipart, &tmp, false, state.process_timeout).await?;
+    upload.reject_unknown_fields(&[])?;
+    let password = input_password(upload.password.as_deref())?;
+
+    match qpdf_check_encryption_with_timeout(&tmp, &upload.path, password, state.process_timeout)
+        .await?
+    {
+        InputEncryption::PasswordRequired if password.is_some() => {
+            return Err(AppError::Unprocessable(format!(
+                "Wrong password for {}",
+                upload.file_name
+            )));
+        }
+        // Without the password nothing but the encryption itself can be read.
+        InputEncryption::PasswordRequired => {
+            info!(file = %upload.file_name, "inspected password-protected document");
+            return Ok(Json(LockedInspectReport {
+                encryption: EncryptionInfo::password_required(),
+            })
+            .into_response());
+        }
+        InputEncryption::None | InputEncryption::Unlocked => {}
+    }
+
+    let report =
+        crate::pdf::qpdf_inspect_with_timeout(&tmp, &upload.path, password, state.process_timeout)
+            .await?;
+    info!(pages = report.pages, file = %upload.file_name, "inspected document");
+    Ok(Json(report).into_response())
+}
+
 pub(crate) async fn merge(
     State(state): State<AppState>,
     cookies: Cookies,
@@ -105,17 +182,42 @@ pub(crate) async fn merge(
 ) -> Result<Response, AppError> {
     let _username = state.require_auth(&cookies)?;
 
-    let mut multipart = multipart.map_err(|e| {
-        error!(error = %e, "multipart parse failed");
-        AppError::BadRequest("Error parsing multipart/form-data request".to_string())
-    })?;
+    let mut multipart = multipart_or_bad_request(multipart)?;
 
     let mut quality: u8 = 80;
     let mut linearize: bool = false;
+    let mut output_profile = OutputProfile::Pdf;
+    let mut color_mode = ColorMode::Color;
+    let mut mono_threshold: Option<f64> = None;
+    let mut mono_raster: bool = false;
     let mut layout_json: Option<String> = None;
+    let mut user_password: Option<String> = None;
+    let mut owner_password: Option<String> = None;
+    let mut permissions: Option<OutputPermissions> = None;
+    let mut watermark_fields: HashMap<String, String> = HashMap::new();
+    let mut imposition_fields: HashMap<String, String> = HashMap::new();
+    let mut page_size: Option<String> = None;
+    let mut page_fit: Option<String> = None;
+    let mut stamping: Option<StampingOptions> = None;
+    let mut metadata: Option<DocumentMetadata> = None;
+    let mut bookmarks: bool = false;
+    let mut preserve_bookmarks: bool = false;
+    let mut titles_by_id: HashMap<String, String> = HashMap::new();
+    let mut image_fields: HashMap<String, String> = HashMap::new();
+    let mut ocr: bool = false;
+    let mut ocr_language: String = "eng".to_string();
+    let mut image_docs: HashSet<String> = HashSet::new();
+    let mut legacy_images: HashSet<usize> = HashSet::new();
     let tmp = TempDir::new().map_err(|e| AppError::Internal(e.to_string()))?;
     let mut input_paths_legacy: Vec<PathBuf> = Vec::new();
+    let mut legacy_names: Vec<String> = Vec::new();
     let mut inputs_by_id: HashMap<String, PathBuf> = HashMap::new();
+    let mut names_by_id: HashMap<String, String> = HashMap::new();
+    let mut passwords_by_id: HashMap<String, String> = HashMap::new();
+    let mut crops_by_id: HashMap<String, CropSpec> = HashMap::new();
+    let mut remove_blank_pages: bool = false;
+    let mut blank_threshold: Option<f64> = None;
+    let mut flatten: Option<FlattenMode> = None;
 
     while let Some(mut field) = multipart
         .next_field()
@@ -124,10 +226,7 @@ pub(crate) async fn merge(
     {
         let name = field.name().unwrap_or("").to_string();
         if name == "quality" {
-            let value = field
-                .text()
-                .await
-                .map_err(|e| AppError::BadRequest(e.to_string()))?;
+            let value = field_text(field).await?;
             quality = value
                 .trim()
                 .parse::<u8>()
@@ -135,34 +234,158 @@ pub(crate) async fn merge(
             continue;
         }
         if name == "linearize" {
-            let value = field
-                .text()
-                .await
-                .map_err(|e| AppError::BadRequest(e.to_string()))?;
+            let value = field_text(field).await?;
             linearize = parse_bool_loose(&value);
             continue;
         }
+        if name == "output_profile" {
+            let value = field_text(field).await?;
+            output_profile = OutputProfile::parse(&value)?;
+            continue;
+        }
+        if name == "color_mode" {
+            let value = field_text(field).await?;
+            color_mode = ColorMode::parse(&value)?;
+            continue;
+        }
+        if name == "mono_threshold" {
+            let value = field_text(field).await?;
+            let threshold = value
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|t| (0.0..=1.0).contains(t))
+                .ok_or_else(|| {
+                    AppError::BadRequest(
+                        "Invalid mono threshold (expected a number between 0 and 1)".to_string(),
+                    )
+                })?;
+            mono_threshold = Some(threshold);
+            continue;
+        }
+        if name == "mono_raster" {
+            let value = field_text(field).await?;
+            mono_raster = parse_bool_loose(&value);
+            continue;
+        }
         if name == "layout" {
-            layout_json = Some(
-                field
-                    .text()
-                    .await
-                    .map_err(|e| AppError::BadRequest(e.to_string()))?,
-            );
+            layout_json = Some(field_text(field).await?);
+            continue;
+        }
+        if name == "user_password" {
+            user_password = Some(field_text(field).await?);
+            continue;
+        }
+        if name == "owner_password" {
+            owner_password = Some(field_text(field).await?);
+            continue;
+        }
+        if name == "permissions" {
+            let value = field_text(field).await?;
+            permissions = Some(OutputPermissions::parse(&value)?);
+            continue;
+        }
+        if name == "stamping" {
+            let value = field_text(field).await?;
+            stamping = Some(StampingOptions::parse(&value)?);
+            continue;
+        }
+        if name == "metadata" {
+            let value = field_text(field).await?;
+            metadata = Some(DocumentMetadata::parse(&value)?);
+            continue;
+        }
+        if name == "bookmarks" {
+            let value = field_text(field).await?;
+            bookmarks = parse_bool_loose(&value);
+            continue;
+        }
+        if name == "preserve_bookmarks" {
+            let value = field_text(field).await?;
+            preserve_bookmarks = parse_bool_loose(&value);
+            continue;
+        }
+        if let Some(doc_id) = name.strip_prefix("title_") {
+            let doc_id = doc_id.to_string();
+            titles_by_id.insert(doc_id, field_text(field).await?);
+            continue;
+        }
+        if name == "ocr" {
+            let value = field_text(field).await?;
+            ocr = parse_bool_loose(&value);
+            continue;
+        }
+        if name == "ocr_language" {
+            let value = field_text(field).await?;
+            ocr_language = parse_ocr_language(&value)?;
+            continue;
+        }
+        if name.starts_with("image_") {
+            let value = field_text(field).await?;
+            image_fields.insert(name, value);
+            continue;
+        }
+        if name == "remove_blank_pages" {
+            let value = field_text(field).await?;
+            remove_blank_pages = parse_bool_loose(&value);
+            continue;
+        }
+        if name == "blank_threshold" {
+            let value = field_text(field).await?;
+            let threshold = value
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|t| (0.0..=100.0).contains(t))
+                .ok_or_else(|| {
+                    AppError::BadRequest(
+                        "Invalid blank threshold (expected a percentage between 0 and 100)"
+                            .to_string(),
+                    )
+                })?;
+            blank_threshold = Some(threshold);
+            continue;
+        }
+        if name == "flatten" {
+            let value = field_text(field).await?;
+            flatten = match value.trim() {
+                "" | "none" => None,
+                v => Some(FlattenMode::parse(v)?),
+            };
+            continue;
+        }
+        if name == "page_size" {
+            page_size = Some(field_text(field).await?);
+            continue;
+        }
+        if name == "page_fit" {
+            page_fit = Some(field_text(field).await?);
+            continue;
+        }
+        if name.starts_with("imposition") {
+            let value = field_text(field).await?;
+            imposition_fields.insert(name, value);
+            continue;
+        }
+        if name.starts_with("watermark") {
+            let value = field_text(field).await?;
+            watermark_fields.insert(name, value);
+            continue;
+        }
+        if let Some(doc_id) = name.strip_prefix("crop_") {
+            let doc_id = doc_id.to_string();
+            let value = field_text(field).await?;
+            crops_by_id.insert(doc_id, CropSpec::parse(&value)?);
+            continue;
+        }
+        if let Some(doc_id) = name.strip_prefix("password_") {
+            let doc_id = doc_id.to_string();
+            passwords_by_id.insert(doc_id, field_text(field).await?);
             continue;
         }
 
-        let content_type = field
-            .content_type()
-            .map(|m| m.split(';').next().unwrap_or("").trim().to_string())
-            .unwrap_or_default();
         let file_name = field.file_name().unwrap_or("file.pdf").to_string();
-
-        if !content_type.is_empty() && content_type != mime::APPLICATION_PDF.essence_str() {
-            return Err(AppError::BadRequest(format!(
-                "Only PDF files are allowed (got {content_type} for {file_name})"
-            )));
-        }
+        ensure_upload_content_type(&field, &file_name, true)?;
 
         let (doc_id, legacy_idx) = if let Some(rest) = name.strip_prefix("file_") {
             (rest.to_string(), None)
@@ -188,27 +411,53 @@ pub(crate) async fn merge(
             )));
         }
 
-        let path = tmp.path().join(format!("in_{}.pdf", uuid::Uuid::new_v4()));
-        let written = write_multipart_field_to_file(&mut field, &path).await?;
-        if written > MAX_FILE_BYTES {
-            return Err(AppError::BadRequest(format!(
-                "{file_name} is too large (max {} MB)",
-                MAX_FILE_BYTES / 1024 / 1024
-            )));
-        }
+        let path = tmp.path().join(format!("in_{}", uuid::Uuid::new_v4()));
+        let kind = save_upload_field(&mut field, &path, &file_name, true).await?;
 
-        if !looks_like_pdf(&path).await? {
-            return Err(AppError::BadRequest(format!(
-                "{file_name} does not look like a PDF"
-            )));
-        }
-
-        if legacy_idx.is_some() {
+        if let Some(idx) = legacy_idx {
             input_paths_legacy.push(path);
+            legacy_names.push(file_name);
+            if kind == UploadKind::Image {
+                legacy_images.insert(idx);
+            }
         } else if inputs_by_id.insert(doc_id.clone(), path).is_some() {
             return Err(AppError::BadRequest(format!(
                 "Duplicate document id: {doc_id}"
             )));
+        } else {
+            if kind == UploadKind::Image {
+                image_docs.insert(doc_id.clone());
+            }
+            names_by_id.insert(doc_id, file_name);
+        }
+    }
+
+    // Image options may arrive after the image parts, so conversion waits until the
+    // whole form has been read.
+    let image_options = ImageToPdfOptions::from_fields(&image_fields)?;
+    for (doc, path) in inputs_by_id.iter_mut() {
+        if image_docs.contains(doc) {
+            let file_name = names_by_id.get(doc).map(String::as_str).unwrap_or(doc);
+            *path = crate::pdf::img2pdf_convert_with_timeout(
+                &tmp,
+                path,
+                file_name,
+                &image_options,
+                state.process_timeout,
+            )
+            .await?;
+        }
+    }
+    for (idx, (path, file_name)) in input_paths_legacy.iter_mut().zip(&legacy_names).enumerate() {
+        if legacy_images.contains(&idx) {
+            *path = crate::pdf::img2pdf_convert_with_timeout(
+                &tmp,
+                path,
+                file_name,
+                &image_options,
+                state.process_timeout,
+            )
+            .await?;
         }
     }
 
@@ -216,82 +465,1605 @@ pub(crate) async fn merge(
         return Err(AppError::BadRequest("No PDF files uploaded".to_string()));
     }
 
+    if let Some(doc) = passwords_by_id
+        .keys()
+        .find(|doc| !inputs_by_id.contains_key(*doc) || image_docs.contains(*doc))
+    {
+        return Err(AppError::BadRequest(format!(
+            "Password provided for unknown doc id: {doc}"
+        )));
+    }
+    for (doc, path) in inputs_by_id.iter_mut() {
+        let file_name = names_by_id
+            .get(doc)
+            .map(String::as_str)
+            .unwrap_or("file.pdf");
+        *path = unlock_input(
+            &tmp,
+            path.clone(),
+            passwords_by_id.get(doc).map(String::as_str),
+            file_name,
+            Some(&format!("password_{doc}")),
+            state.process_timeout,
+        )
+        .await?;
+    }
+    for (path, file_name) in input_paths_legacy.iter_mut().zip(&legacy_names) {
+        *path = unlock_input(
+            &tmp,
+            path.clone(),
+            None,
+            file_name,
+            None,
+            state.process_timeout,
+        )
+        .await?;
+    }
+
     if !(10..=100).contains(&quality) {
         return Err(AppError::BadRequest(
             "Quality must be between 10 and 100".to_string(),
         ));
     }
 
-    let output_path = if let Some(layout_json) = layout_json {
-        let layout: Vec<MergePageRef> = serde_json::from_str(&layout_json)
-            .map_err(|_| AppError::BadRequest("Invalid layout".to_string()))?;
-        if layout.is_empty() {
-            return Err(AppError::BadRequest("Layout is empty".to_string()));
-        }
-        if inputs_by_id.is_empty() {
+    if mono_threshold.is_some() && color_mode != ColorMode::Mono {
+        return Err(AppError::BadRequest(
+            "mono_threshold requires color_mode=mono".to_string(),
+        ));
+    }
+    if mono_raster && color_mode != ColorMode::Mono {
+        return Err(AppError::BadRequest(
+            "mono_raster requires color_mode=mono".to_string(),
+        ));
+    }
+    let mono_threshold = (color_mode == ColorMode::Mono).then(|| mono_threshold.unwrap_or(0.5));
+    // Rasterized pages are thresholded already; pdfwrite only thresholds the rest.
+    let raster_threshold = mono_threshold.filter(|_| mono_raster);
+    let output_options = PdfOutputOptions {
+        profile: output_profile,
+        icc_profile: &state.pdfa_icc_profile,
+        color: color_mode,
+        mono_threshold: mono_threshold.filter(|_| !mono_raster),
+    };
+
+    let page_size = match (page_size, page_fit) {
+        (Some(size), fit) => Some(PageSizeOptions::parse(&size, fit.as_deref())?),
+        (None, Some(_)) => {
             return Err(AppError::BadRequest(
-                "Layout provided but no file_* parts found".to_string(),
-            ));
+                "page_fit requires page_size".to_string(),
+            ))
         }
+        (None, None) => None,
+    };
+    if blank_threshold.is_some() && !remove_blank_pages {
+        return Err(AppError::BadRequest(
+            "blank_threshold requires remove_blank_pages".to_string(),
+        ));
+    }
+    if layout_json.is_none() && !inputs_by_id.is_empty() {
+        return Err(AppError::BadRequest(
+            "file_* parts require a layout".to_string(),
+        ));
+    }
+    // crop_<docid> names a file_<docid> part, and those are only merged via a layout.
+    if !crops_by_id.is_empty() && layout_json.is_none() {
+        return Err(AppError::BadRequest("crop requires a layout".to_string()));
+    }
+    let imposition = ImpositionOptions::from_fields(&imposition_fields)?;
 
-        let mut pages_by_doc: HashMap<String, usize> = HashMap::new();
-        for (doc, path) in &inputs_by_id {
-            let pages =
-                crate::pdf::qpdf_show_npages_with_timeout(path, state.process_timeout).await?;
-            pages_by_doc.insert(doc.clone(), pages);
-        }
+    let encryption = encryption_options(user_password, owner_password, permissions)?;
+    if encryption.is_some() && output_profile.pdfa_part().is_some() {
+        return Err(AppError::BadRequest(
+            "PDF/A output cannot be encrypted".to_string(),
+        ));
+    }
+    let mut stamps = StampPlan {
+        watermark: WatermarkOptions::from_fields(&watermark_fields)?,
+        stamping,
+        metadata,
+        outline: Vec::new(),
+    };
+
+    // Damaged inputs are only dealt with once the pipeline fails on something:
+    // then every input goes through `repair_pdf_with_timeout` and the pipeline
+    // runs once more, so healthy uploads never pay for `qpdf --check`.
+    let mut repaired: Option<Vec<RepairedInput>> = None;
+    let mut removed_pages: Option<Vec<RemovedPage>>;
+    let merged_path = loop {
+        removed_pages = None;
+        stamps.outline.clear();
+        let attempt = async {
+            let merged_path = if let Some(layout_json) = layout_json.as_deref() {
+                let layout: Vec<LayoutEntry> = serde_json::from_str(layout_json)
+                    .map_err(|_| AppError::BadRequest("Invalid layout".to_string()))?;
+                if layout.is_empty() {
+                    return Err(AppError::BadRequest("Layout is empty".to_string()));
+                }
+                if inputs_by_id.is_empty() {
+                    return Err(AppError::BadRequest(
+                        "Layout provided but no file_* parts found".to_string(),
+                    ));
+                }
+                if let Some(doc) = crops_by_id
+                    .keys()
+                    .find(|doc| !inputs_by_id.contains_key(*doc))
+                {
+                    return Err(AppError::BadRequest(format!(
+                        "Crop provided for unknown doc id: {doc}"
+                    )));
+                }
+
+                let mut pages_by_doc: HashMap<String, usize> = HashMap::new();
+                for (doc, path) in &inputs_by_id {
+                    let pages =
+                        crate::pdf::qpdf_show_npages_with_timeout(path, state.process_timeout)
+                            .await?;
+                    pages_by_doc.insert(doc.clone(), pages);
+                }
 
-        for r in &layout {
-            let Some(max_pages) = pages_by_doc.get(&r.doc) else {
-                return Err(AppError::BadRequest(format!(
-                    "Layout references unknown doc id: {}",
-                    r.doc
-                )));
+                for (idx, entry) in layout.iter().enumerate() {
+                    let r = match entry {
+                        LayoutEntry::Page(r) => r,
+                        LayoutEntry::Blank(b) => {
+                            if !b.blank {
+                                return Err(AppError::BadRequest(format!(
+                                    "Invalid layout entry {}",
+                                    idx + 1
+                                )));
+                            }
+                            parse_layout_page_size(b.size.as_deref())?;
+                            continue;
+                        }
+                        LayoutEntry::Separator(sep) => {
+                            if !sep.separator {
+                                return Err(AppError::BadRequest(format!(
+                                    "Invalid layout entry {}",
+                                    idx + 1
+                                )));
+                            }
+                            parse_layout_page_size(sep.size.as_deref())?;
+                            if sep.title.is_none()
+                                && layout[idx..].iter().all(|e| e.page_ref().is_none())
+                            {
+                                return Err(AppError::BadRequest(format!(
+                                    "Separator at layout entry {} has no document after it",
+                                    idx + 1
+                                )));
+                            }
+                            continue;
+                        }
+                    };
+                    let Some(max_pages) = pages_by_doc.get(&r.doc) else {
+                        return Err(AppError::BadRequest(format!(
+                            "Layout references unknown doc id: {}",
+                            r.doc
+                        )));
+                    };
+                    if r.page == 0 || r.page > *max_pages {
+                        return Err(AppError::BadRequest(format!(
+                            "Invalid page {} for doc {} (max {})",
+                            r.page, r.doc, max_pages
+                        )));
+                    }
+                    if let Some(spec) = &r.rotate {
+                        if spec.to_rotation().is_none() {
+                            return Err(AppError::BadRequest(format!(
+                        "Invalid rotation {} for page {} of doc {} (expected 0, 90, 180 or 270)",
+                        spec.display(),
+                        r.page,
+                        r.doc
+                    )));
+                        }
+                    }
+                    if let Some(crop) = &r.crop {
+                        crop.validate().map_err(|e| match e {
+                            AppError::BadRequest(msg) => AppError::BadRequest(format!(
+                                "{msg} (page {} of doc {})",
+                                r.page, r.doc
+                            )),
+                            other => other,
+                        })?;
+                    }
+                }
+
+                if layout.iter().all(|e| e.page_ref().is_none()) {
+                    return Err(AppError::BadRequest(
+                        "Layout has no document pages".to_string(),
+                    ));
+                }
+                let layout_pages = || {
+                    layout
+                        .iter()
+                        .enumerate()
+                        .filter_map(|(idx, e)| e.page_ref().map(|r| (idx, r)))
+                };
+
+                if bookmarks || preserve_bookmarks {
+                    // First output page for every (doc, source page) the layout keeps.
+                    let mut output_pages: HashMap<(&str, usize), usize> = HashMap::new();
+                    for (idx, r) in layout_pages() {
+                        output_pages
+                            .entry((r.doc.as_str(), r.page))
+                            .or_insert(idx + 1);
+                    }
+
+                    let mut seen: HashSet<&str> = HashSet::new();
+                    for (idx, r) in layout_pages() {
+                        if !seen.insert(r.doc.as_str()) {
+                            continue;
+                        }
+                        let children = if preserve_bookmarks {
+                            let source = crate::pdf::qpdf_outline_with_timeout(
+                                &inputs_by_id[&r.doc],
+                                state.process_timeout,
+                            )
+                            .await?;
+                            remap_outline(&source, &|page| {
+                                output_pages.get(&(r.doc.as_str(), page)).copied()
+                            })
+                        } else {
+                            Vec::new()
+                        };
+                        stamps.outline.push(OutlineEntry {
+                            title: doc_title(&r.doc, &titles_by_id, &names_by_id),
+                            page: idx + 1,
+                            children,
+                        });
+                    }
+                }
+
+                let generated = generate_layout_pages(
+                    &tmp,
+                    &layout,
+                    &inputs_by_id,
+                    &titles_by_id,
+                    &names_by_id,
+                    state.process_timeout,
+                )
+                .await?;
+                let assembled = crate::pdf::qpdf_assemble_pages_with_timeout(
+                    &tmp,
+                    &inputs_by_id,
+                    &layout,
+                    &generated,
+                    state.process_timeout,
+                )
+                .await?;
+                let assembled = if let Some(mode) = flatten {
+                    crate::pdf::qpdf_flatten_with_timeout(
+                        &tmp,
+                        &assembled,
+                        mode,
+                        state.process_timeout,
+                    )
+                    .await?
+                } else {
+                    assembled
+                };
+                let crops: Vec<Option<CropSpec>> = layout
+                    .iter()
+                    .map(|e| {
+                        e.page_ref().and_then(|r| {
+                            r.crop.clone().or_else(|| crops_by_id.get(&r.doc).cloned())
+                        })
+                    })
+                    .collect();
+                let assembled = if crops.iter().any(Option::is_some) {
+                    crate::pdf::crop_pages_with_timeout(
+                        &tmp,
+                        &assembled,
+                        &crops,
+                        state.process_timeout,
+                    )
+                    .await?
+                } else {
+                    assembled
+                };
+                let assembled = if remove_blank_pages {
+                    let (path, blank) = crate::pdf::remove_blank_pages_with_timeout(
+                        &tmp,
+                        &assembled,
+                        blank_threshold.unwrap_or(0.1),
+                        &generated.keys().map(|idx| idx + 1).collect(),
+                        state.process_timeout,
+                    )
+                    .await?;
+                    // Bookmarks on a removed page move to the next page that survived.
+                    let surviving: Vec<usize> = (1..=layout.len())
+                        .filter(|page| blank.binary_search(page).is_err())
+                        .collect();
+                    move_outline_pages(&mut stamps.outline, &|page| {
+                        surviving
+                            .partition_point(|p| *p < page)
+                            .min(surviving.len() - 1)
+                            + 1
+                    });
+                    info!(pages = ?blank, "blank pages removed");
+                    removed_pages = Some(
+                        blank
+                            .iter()
+                            .filter_map(|page| layout[page - 1].page_ref())
+                            .map(|r| RemovedPage {
+                                doc: r.doc.clone(),
+                                page: r.page,
+                            })
+                            .collect(),
+                    );
+                    path
+                } else {
+                    assembled
+                };
+                let assembled = if let Some(threshold) = raster_threshold {
+                    crate::pdf::mono_rasterize_with_timeout(
+                        &tmp,
+                        &assembled,
+                        threshold,
+                        state.process_timeout,
+                    )
+                    .await?
+                } else {
+                    assembled
+                };
+                let assembled = if ocr {
+                    let (path, ocr_pages) = crate::pdf::ocr_pages_without_text_with_timeout(
+                        &tmp,
+                        &assembled,
+                        &ocr_language,
+                        MAX_RENDER_PAGES,
+                        state.process_timeout,
+                    )
+                    .await?;
+                    info!(pages = ?ocr_pages, language = %ocr_language, "ocr applied");
+                    path
+                } else {
+                    assembled
+                };
+                let assembled = if let Some(page_size) = &page_size {
+                    crate::pdf::normalize_page_size_with_timeout(
+                        &tmp,
+                        &assembled,
+                        page_size,
+                        state.process_timeout,
+                    )
+                    .await?
+                } else {
+                    assembled
+                };
+                let assembled = if let Some(imposition) = &imposition {
+                    let (path, sheet_of_page) = crate::pdf::impose_pages_with_timeout(
+                        &tmp,
+                        &assembled,
+                        imposition,
+                        state.process_timeout,
+                    )
+                    .await?;
+                    move_outline_pages(&mut stamps.outline, &|page| {
+                        sheet_of_page.get(&page).copied().unwrap_or(1)
+                    });
+                    path
+                } else {
+                    assembled
+                };
+                crate::pdf::merge_with_ghostscript_to_file_with_timeout(
+                    &tmp,
+                    &[assembled],
+                    quality,
+                    &output_options,
+                    state.process_timeout,
+                )
+                .await?
+            } else {
+                // The inputs stay untouched for a retry after repair.
+                let mut input_paths_legacy = input_paths_legacy.clone();
+                let mut page_counts = Vec::with_capacity(input_paths_legacy.len());
+                for path in &input_paths_legacy {
+                    page_counts.push(
+                        crate::pdf::qpdf_show_npages_with_timeout(path, state.process_timeout)
+                            .await?,
+                    );
+                }
+                if bookmarks || preserve_bookmarks {
+                    let mut offset = 0;
+                    for ((path, file_name), &pages) in input_paths_legacy
+                        .iter()
+                        .zip(&legacy_names)
+                        .zip(&page_counts)
+                    {
+                        let children = if preserve_bookmarks {
+                            let source =
+                                crate::pdf::qpdf_outline_with_timeout(path, state.process_timeout)
+                                    .await?;
+                            remap_outline(&source, &|page| (page <= pages).then_some(offset + page))
+                        } else {
+                            Vec::new()
+                        };
+                        stamps.outline.push(OutlineEntry {
+                            title: file_name.clone(),
+                            page: offset + 1,
+                            children,
+                        });
+                        offset += pages;
+                    }
+                }
+
+                if let Some(mode) = flatten {
+                    for path in input_paths_legacy.iter_mut() {
+                        *path = crate::pdf::qpdf_flatten_with_timeout(
+                            &tmp,
+                            path,
+                            mode,
+                            state.process_timeout,
+                        )
+                        .await?;
+                    }
+                }
+
+                // From here on the inputs are one document, so the steps behave as
+                // they do for a layout that lists every page in upload order.
+                let joined = if input_paths_legacy.len() == 1 {
+                    input_paths_legacy.remove(0)
+                } else {
+                    crate::pdf::qpdf_concat_with_timeout(
+                        &tmp,
+                        &input_paths_legacy,
+                        state.process_timeout,
+                    )
+                    .await?
+                };
+                let joined = if remove_blank_pages {
+                    let (path, blank) = crate::pdf::remove_blank_pages_with_timeout(
+                        &tmp,
+                        &joined,
+                        blank_threshold.unwrap_or(0.1),
+                        &HashSet::new(),
+                        state.process_timeout,
+                    )
+                    .await?;
+                    let total: usize = page_counts.iter().sum();
+                    let surviving: Vec<usize> = (1..=total)
+                        .filter(|page| blank.binary_search(page).is_err())
+                        .collect();
+                    move_outline_pages(&mut stamps.outline, &|page| {
+                        surviving
+                            .partition_point(|p| *p < page)
+                            .min(surviving.len() - 1)
+                            + 1
+                    });
+                    info!(pages = ?blank, "blank pages removed");
+                    // Uploads without a doc id are reported by filename.
+                    removed_pages = Some(
+                        blank
+                            .iter()
+                            .map(|&page| {
+                                let mut page = page;
+                                let mut doc = 0;
+                                while doc + 1 < page_counts.len() && page > page_counts[doc] {
+                                    page -= page_counts[doc];
+                                    doc += 1;
+                                }
+                                RemovedPage {
+                                    doc: legacy_names[doc].clone(),
+                                    page,
+                                }
+                            })
+                            .collect(),
+                    );
+                    path
+                } else {
+                    joined
+                };
+                let joined = if let Some(threshold) = raster_threshold {
+                    crate::pdf::mono_rasterize_with_timeout(
+                        &tmp,
+                        &joined,
+                        threshold,
+                        state.process_timeout,
+                    )
+                    .await?
+                } else {
+                    joined
+                };
+                let joined = if ocr {
+                    let (path, ocr_pages) = crate::pdf::ocr_pages_without_text_with_timeout(
+                        &tmp,
+                        &joined,
+                        &ocr_language,
+                        MAX_RENDER_PAGES,
+                        state.process_timeout,
+                    )
+                    .await?;
+                    info!(pages = ?ocr_pages, language = %ocr_language, "ocr applied");
+                    path
+                } else {
+                    joined
+                };
+                let joined = if let Some(page_size) = &page_size {
+                    crate::pdf::normalize_page_size_with_timeout(
+                        &tmp,
+                        &joined,
+                        page_size,
+                        state.process_timeout,
+                    )
+                    .await?
+                } else {
+                    joined
+                };
+                let joined = if let Some(imposition) = &imposition {
+                    let (path, sheet_of_page) = crate::pdf::impose_pages_with_timeout(
+                        &tmp,
+                        &joined,
+                        imposition,
+                        state.process_timeout,
+                    )
+                    .await?;
+                    move_outline_pages(&mut stamps.outline, &|page| {
+                        sheet_of_page.get(&page).copied().unwrap_or(1)
+                    });
+                    path
+                } else {
+                    joined
+                };
+
+                crate::pdf::merge_with_ghostscript_to_file_with_timeout(
+                    &tmp,
+                    &[joined],
+                    quality,
+                    &output_options,
+                    state.process_timeout,
+                )
+                .await?
             };
-            if r.page == 0 || r.page > *max_pages {
-                return Err(AppError::BadRequest(format!(
-                    "Invalid page {} for doc {} (max {})",
-                    r.page, r.doc, max_pages
-                )));
+            Ok::<PathBuf, AppError>(merged_path)
+        }
+        .await;
+
+        match attempt {
+            Err(AppError::Internal(e)) if repaired.is_none() => {
+                let mut fixed: Vec<RepairedInput> = Vec::new();
+                let inputs = inputs_by_id
+                    .iter_mut()
+                    .map(|(doc, path)| {
+                        let file_name = names_by_id.get(doc).map(String::as_str).unwrap_or(doc);
+                        (path, file_name)
+                    })
+                    .chain(
+                        input_paths_legacy
+                            .iter_mut()
+                            .zip(legacy_names.iter().map(String::as_str)),
+                    );
+                for (path, file_name) in inputs {
+                    let (repaired_path, report) =
+                        repair_pdf_with_timeout(&tmp, path, file_name, state.process_timeout)
+                            .await?;
+                    if report.strategy != RepairStrategy::None {
+                        info!(file = %file_name, strategy = report.strategy.label(), "input repaired");
+                        fixed.push(RepairedInput {
+                            file: file_name.to_string(),
+                            strategy: report.strategy,
+                        });
+                    }
+                    *path = repaired_path;
+                }
+                // Nothing was damaged, so the failure is not the inputs' fault.
+                if fixed.is_empty() {
+                    return Err(AppError::Internal(e));
+                }
+                info!(error = %e, "merge failed, retrying with repaired inputs");
+                repaired = Some(fixed);
             }
+            result => break result?,
         }
+    };
 
-        let assembled = crate::pdf::qpdf_assemble_pages_with_timeout(
+    // The inputs' Info and XMP are dropped between the two Ghostscript passes; the
+    // stamp pass then writes fresh ones from `metadata`.
+    let merged_path = if stamps.metadata.as_ref().is_some_and(|m| m.strip) {
+        crate::pdf::qpdf_strip_metadata_with_timeout(&tmp, &merged_path, state.process_timeout)
+            .await?
+    } else {
+        merged_path
+    };
+    let merged_path = if stamps.is_empty() {
+        merged_path
+    } else {
+        let total_pages =
+            crate::pdf::qpdf_show_npages_with_timeout(&merged_path, state.process_timeout).await?;
+        let postscript = stamps.to_postscript(total_pages);
+        crate::pdf::stamp_with_ghostscript_to_file_with_timeout(
             &tmp,
-            &inputs_by_id,
-            &layout,
+            &merged_path,
+            &postscript,
+            &output_options,
             state.process_timeout,
         )
-        .await?;
-        let merged_path = crate::pdf::merge_with_ghostscript_to_file_with_timeout(
+        .await?
+    };
+
+    let output_path = if let Some(encryption) = &encryption {
+        crate::pdf::qpdf_encrypt_file_with_timeout(
             &tmp,
-            &[assembled],
-            quality,
+            &merged_path,
+            encryption,
+            linearize,
             state.process_timeout,
         )
-        .await?;
-        if linearize {
-            crate::pdf::qpdf_linearize_file_with_timeout(&tmp, &merged_path, state.process_timeout)
+        .await?
+    } else if linearize {
+        crate::pdf::qpdf_linearize_file_with_timeout(&tmp, &merged_path, state.process_timeout)
+            .await?
+    } else {
+        merged_path
+    };
+
+    // The identification marks are always checked; conformance needs veraPDF.
+    let pdfa_checks = if output_profile.pdfa_part().is_some() {
+        let identified =
+            crate::pdf::pdfa_identification_present(&output_path, output_profile).await?;
+        if !identified {
+            error!(
+                profile = output_profile.label(),
+                "PDF/A identification missing from output"
+            );
+        }
+        let conformance = match state.verapdf_path.as_deref() {
+            Some(verapdf) => {
+                crate::pdf::verapdf_validate_with_timeout(
+                    verapdf,
+                    &output_path,
+                    output_profile,
+                    state.process_timeout,
+                )
                 .await?
-        } else {
-            merged_path
+            }
+            None => crate::pdf::PdfaConformance::Unchecked,
+        };
+        if conformance == crate::pdf::PdfaConformance::Fail {
+            error!(
+                profile = output_profile.label(),
+                "output failed PDF/A validation"
+            );
         }
+        Some((identified, conformance))
     } else {
-        let merged_path = crate::pdf::merge_with_ghostscript_to_file_with_timeout(
-            &tmp,
-            &input_paths_legacy,
-            quality,
-            state.process_timeout,
-        )
-        .await?;
-        if linearize {
-            crate::pdf::qpdf_linearize_file_with_timeout(&tmp, &merged_path, state.process_timeout)
+        None
+    };
+
+    let mut res = stream_file_response(
+        tmp,
+        output_path,
+        "application/pdf",
+        "attachment; filename=\"merged.pdf\"",
+    )
+    .await?;
+    res.headers_mut().insert(
+        "x-output-profile",
+        HeaderValue::from_static(output_profile.label()),
+    );
+    if let Some(removed) = &removed_pages {
+        res.headers_mut().insert(
+            "x-removed-blank-pages",
+            HeaderValue::from_str(&ascii_json(removed)?)
+                .map_err(|e| AppError::Internal(e.to_string()))?,
+        );
+    }
+    if let Some(repaired) = &repaired {
+        res.headers_mut().insert(
+            "x-repaired-inputs",
+            HeaderValue::from_str(&ascii_json(&repaired)?)
+                .map_err(|e| AppError::Internal(e.to_string()))?,
+        );
+    }
+    if let Some((identified, conformance)) = pdfa_checks {
+        res.headers_mut().insert(
+            "x-pdfa-identification",
+            HeaderValue::from_static(if identified { "true" } else { "false" }),
+        );
+        res.headers_mut().insert(
+            "x-pdfa-conformance",
+            HeaderValue::from_static(conformance.label()),
+        );
+    }
+    Ok(res)
+}
+
+pub(crate) async fn split(
+    State(state): State<AppState>,
+    cookies: Cookies,
+    multipart: Result<Multipart, MultipartRejection>,
+) -> Result<Response, AppError> {
+    let _username = state.require_auth(&cookies)?;
+
+    let mut multipart = multipart_or_bad_request(multipart)?;
+
+    let tmp = TempDir::new().map_err(|e| AppError::Internal(e.to_string()))?;
+    let upload = read_single_upload(&mut multipart, &tmp, false, state.process_timeout).await?;
+    upload.reject_unknown_fields(&["ranges", "every"])?;
+    let ranges_spec = upload.fields.get("ranges").cloned();
+    let every = match upload.fields.get("every") {
+        Some(value) => Some(
+            value
+                .trim()
+                .parse::<usize>()
+                .map_err(|_| AppError::BadRequest("Invalid page count per part".to_string()))?,
+        ),
+        None => None,
+    };
+    let (path, file_name) = (upload.path, upload.file_name);
+
+    let pages = crate::pdf::qpdf_show_npages_with_timeout(&path, state.process_timeout).await?;
+    let ranges = match (ranges_spec, every) {
+        (Some(_), Some(_)) => {
+            return Err(AppError::BadRequest(
+                "Provide either ranges or every, not both".to_string(),
+            ))
+        }
+        (Some(spec), None) => parse_page_ranges(&spec, pages)?,
+        (None, Some(n)) => every_n_pages(n, pages)?,
+        (None, None) => return Err(AppError::BadRequest("Missing ranges or every".to_string())),
+    };
+    if ranges.len() > MAX_SPLIT_PARTS {
+        return Err(AppError::BadRequest(format!(
+            "Too many parts (max {MAX_SPLIT_PARTS})"
+        )));
+    }
+
+    let stem = archive_safe_stem(&file_name);
+    let mut entries: Vec<(String, PathBuf)> = Vec::with_capacity(ranges.len());
+    let mut seen: HashSet<PageRange> = HashSet::new();
+    for range in &ranges {
+        if !seen.insert(*range) {
+            return Err(AppError::BadRequest(format!(
+                "Duplicate range: {}",
+                range.label()
+            )));
+        }
+        let part =
+            crate::pdf::qpdf_extract_range_with_timeout(&tmp, &path, range, state.process_timeout)
+                .await?;
+        entries.push((format!("{stem}_{}.pdf", range.label()), part));
+    }
+    info!(
+        pages,
+        parts = entries.len(),
+        file = %file_name,
+        "split document"
+    );
+
+    let zip_path = crate::archive::zip_files(&tmp, entries).await?;
+    stream_file_response(
+        tmp,
+        zip_path,
+        "application/zip",
+        "attachment; filename=\"split.zip\"",
+    )
+    .await
+}
+
+enum ThumbnailOutput {
+    Zip,
+    Json,
+}
+
+#[derive(Serialize)]
+pub(crate) struct Thumbnail {
+    pub(crate) page: usize,
+    pub(crate) data_url: String,
+}
+
+pub(crate) async fn thumbnails(
+    State(state): State<AppState>,
+    cookies: Cookies,
+    multipart: Result<Multipart, MultipartRejection>,
+) -> Result<Response, AppError> {
+    let _username = state.require_auth(&cookies)?;
+
+    let mut multipart = multipart_or_bad_request(multipart)?;
+
+    let tmp = TempDir::new().map_err(|e| AppError::Internal(e.to_string()))?;
+    let upload = read_single_upload(&mut multipart, &tmp, false, state.process_timeout).await?;
+    upload.reject_unknown_fields(&["pages", "dpi", "output", "format"])?;
+
+    let dpi = match upload.fields.get("dpi") {
+        Some(value) => value
+            .trim()
+            .parse::<u32>()
+            .map_err(|_| AppError::BadRequest("Invalid dpi".to_string()))?,
+        None => 24,
+    };
+    if !(8..=72).contains(&dpi) {
+        return Err(AppError::BadRequest(
+            "Thumbnail dpi must be between 8 and 72".to_string(),
+        ));
+    }
+    let output = match upload
+        .fields
+        .get("output")
+        .map(|v| v.trim().to_ascii_lowercase())
+        .as_deref()
+    {
+        None | Some("zip") => ThumbnailOutput::Zip,
+        Some("json") => ThumbnailOutput::Json,
+        Some(_) => {
+            return Err(AppError::BadRequest(
+                "Output must be zip or json".to_string(),
+            ))
+        }
+    };
+    let webp = match upload
+        .fields
+        .get("format")
+        .map(|v| v.trim().to_ascii_lowercase())
+        .as_deref()
+    {
+        None | Some("png") => false,
+        Some("webp") => true,
+        Some(_) => {
+            return Err(AppError::BadRequest(
+                "Format must be png or webp".to_string(),
+            ))
+        }
+    };
+
+    let total =
+        crate::pdf::qpdf_show_npages_with_timeout(&upload.path, state.process_timeout).await?;
+    let pages = selected_pages(upload.fields.get("pages").map(String::as_str), total)?;
+    if pages.len() > MAX_RENDER_PAGES {
+        return Err(AppError::BadRequest(format!(
+            "Too many pages (max {MAX_RENDER_PAGES})"
+        )));
+    }
+
+    let rendered = crate::pdf::render_pages_with_ghostscript_with_timeout(
+        &tmp,
+        &upload.path,
+        &pages,
+        &RenderOptions {
+            device: "png16m",
+            extension: "png",
+            dpi,
+            extra_args: Vec::new(),
+        },
+        state.process_timeout,
+    )
+    .await?;
+    // Ghostscript has no WebP device, so the PNGs are converted afterwards.
+    let (rendered, mime, extension) = if webp {
+        let mut converted = Vec::with_capacity(rendered.len());
+        for path in &rendered {
+            converted
+                .push(crate::pdf::cwebp_convert_with_timeout(path, state.process_timeout).await?);
+        }
+        (converted, "image/webp", "webp")
+    } else {
+        (rendered, "image/png", "png")
+    };
+    info!(
+        pages = rendered.len(),
+        file = %upload.file_name,
+        format = extension,
+        "rendered thumbnails"
+    );
+
+    match output {
+        ThumbnailOutput::Json => {
+            let mut out = Vec::with_capacity(rendered.len());
+            for (page, path) in pages.iter().zip(&rendered) {
+                let data = tokio::fs::read(path)
+                    .await
+                    .map_err(|e| AppError::Internal(e.to_string()))?;
+                out.push(Thumbnail {
+                    page: *page,
+                    data_url: format!(
+                        "data:{mime};base64,{}",
+                        base64::engine::general_purpose::STANDARD.encode(data)
+                    ),
+                });
+            }
+            Ok(Json(out).into_response())
+        }
+        ThumbnailOutput::Zip => {
+            let entries = pages
+                .iter()
+                .zip(rendered)
+                .map(|(page, path)| (format!("page_{page}.{extension}"), path))
+                .collect();
+            let zip_path = crate::archive::zip_files(&tmp, entries).await?;
+            stream_file_response(
+                tmp,
+                zip_path,
+                "application/zip",
+                "attachment; filename=\"thumbnails.zip\"",
+            )
+            .await
+        }
+    }
+}
+
+pub(crate) async fn images(
+    State(state): State<AppState>,
+    cookies: Cookies,
+    multipart: Result<Multipart, MultipartRejection>,
+) -> Result<Response, AppError> {
+    let _username = state.require_auth(&cookies)?;
+
+    let mut multipart = multipart_or_bad_request(multipart)?;
+
+    let tmp = TempDir::new().map_err(|e| AppError::Internal(e.to_string()))?;
+    let upload = read_single_upload(&mut multipart, &tmp, false, state.process_timeout).await?;
+    upload.reject_unknown_fields(&["pages", "dpi", "format", "color_mode"])?;
+
+    let dpi = match upload.fields.get("dpi") {
+        Some(value) => value
+            .trim()
+            .parse::<u32>()
+            .map_err(|_| AppError::BadRequest("Invalid dpi".to_string()))?,
+        None => 150,
+    };
+    if !(36..=600).contains(&dpi) {
+        return Err(AppError::BadRequest(
+            "Image dpi must be between 36 and 600".to_string(),
+        ));
+    }
+    let format = match upload.fields.get("format") {
+        Some(value) => ImageFormat::parse(value)?,
+        None => ImageFormat::Png,
+    };
+    let color = match upload.fields.get("color_mode") {
+        Some(value) => ColorMode::parse(value)?,
+        None => ColorMode::Color,
+    };
+    let options = image_render_options(format, color, dpi)?;
+
+    let total =
+        crate::pdf::qpdf_show_npages_with_timeout(&upload.path, state.process_timeout).await?;
+    let pages = selected_pages(upload.fields.get("pages").map(String::as_str), total)?;
+    if pages.len() > MAX_RENDER_PAGES {
+        return Err(AppError::BadRequest(format!(
+            "Too many pages (max {MAX_RENDER_PAGES})"
+        )));
+    }
+
+    let rendered = crate::pdf::render_pages_with_ghostscript_with_timeout(
+        &tmp,
+        &upload.path,
+        &pages,
+        &options,
+        state.process_timeout,
+    )
+    .await?;
+    info!(
+        pages = rendered.len(),
+        dpi,
+        file = %upload.file_name,
+        "exported page images"
+    );
+
+    let stem = archive_safe_stem(&upload.file_name);
+    let entries = pages
+        .iter()
+        .zip(rendered)
+        .map(|(page, path)| (format!("{stem}_page_{page}.{}", options.extension), path))
+        .collect();
+    let zip_path = crate::archive::zip_files(&tmp, entries).await?;
+    stream_file_response(
+        tmp,
+        zip_path,
+        "application/zip",
+        "attachment; filename=\"images.zip\"",
+    )
+    .await
+}
+
+#[derive(Serialize)]
+pub(crate) struct PageText {
+    pub(crate) page: usize,
+    pub(crate) text: String,
+}
+
+pub(crate) async fn text(
+    State(state): State<AppState>,
+    cookies: Cookies,
+    multipart: Result<Multipart, MultipartRejection>,
+) -> Result<Response, AppError> {
+    let _username = state.require_auth(&cookies)?;
+
+    let mut multipart = multipart_or_bad_request(multipart)?;
+
+    let tmp = TempDir::new().map_err(|e| AppError::Internal(e.to_string()))?;
+    let upload = read_single_upload(&mut multipart, &tmp, false, state.process_timeout).await?;
+    upload.reject_unknown_fields(&["pages", "format"])?;
+
+    let as_json = match upload
+        .fields
+        .get("format")
+        .map(|v| v.trim().to_ascii_lowercase())
+        .as_deref()
+    {
+        None | Some("json") => true,
+        Some("text") => false,
+        Some(_) => {
+            return Err(AppError::BadRequest(
+                "Format must be json or text".to_string(),
+            ))
+        }
+    };
+
+    let total =
+        crate::pdf::qpdf_show_npages_with_timeout(&upload.path, state.process_timeout).await?;
+    let pages = selected_pages(upload.fields.get("pages").map(String::as_str), total)?;
+    if pages.len() > MAX_RENDER_PAGES {
+        return Err(AppError::BadRequest(format!(
+            "Too many pages (max {MAX_RENDER_PAGES})"
+        )));
+    }
+
+    let rendered = crate::pdf::render_pages_with_ghostscript_with_timeout(
+        &tmp,
+        &upload.path,
+        &pages,
+        &text_render_options(),
+        state.process_timeout,
+    )
+    .await?;
+
+    let mut out = Vec::with_capacity(rendered.len());
+    for (page, path) in pages.iter().zip(&rendered) {
+        let data = tokio::fs::read(path)
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+        out.push(PageText {
+            page: *page,
+            text: String::from_utf8_lossy(&data).into_owned(),
+        });
+    }
+    info!(
+        pages = out.len(),
+        file = %upload.file_name,
+        "extracted text"
+    );
+
+    if as_json {
+        return Ok(Json(out).into_response());
+    }
+    // Plain text keeps page boundaries as form feeds, like pdftotext does.
+    let body = out
+        .into_iter()
+        .map(|p| p.text)
+        .collect::<Vec<_>>()
+        .join("\u{c}");
+    Ok((
+        [(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; charset=utf-8"),
+        )],
+        body,
+    )
+        .into_response())
+}
+
+pub(crate) async fn repair(
+    State(state): State<AppState>,
+    cookies: Cookies,
+    multipart: Result<Multipart, MultipartRejection>,
+) -> Result<Response, AppError> {
+    let _username = state.require_auth(&cookies)?;
+
+    let mut multipart = multipart_or_bad_request(multipart)?;
+
+    let tmp = TempDir::new().map_err(|e| AppError::Internal(e.to_string()))?;
+    let upload = read_single_upload(&mut multipart, &tmp, false, state.process_timeout).await?;
+    upload.reject_unknown_fields(&[])?;
+
+    let (output_path, report) =
+        repair_pdf_with_timeout(&tmp, &upload.path, &upload.file_name, state.process_timeout)
+            .await?;
+    info!(
+        strategy = report.strategy.label(),
+        warnings = report.warning_count,
+        file = %upload.file_name,
+        "repair finished"
+    );
+
+    let mut res = stream_file_response(
+        tmp,
+        output_path,
+        "application/pdf",
+        "attachment; filename=\"repaired.pdf\"",
+    )
+    .await?;
+    res.headers_mut().insert(
+        "x-repair-strategy",
+        HeaderValue::from_static(report.strategy.label()),
+    );
+    res.headers_mut().insert(
+        "x-repair-warning-count",
+        HeaderValue::from(report.warning_count),
+    );
+    res.headers_mut().insert(
+        "x-repair-warnings",
+        HeaderValue::from_str(&ascii_json(&report.warnings)?)
+            .map_err(|e| AppError::Internal(e.to_string()))?,
+    );
+    Ok(res)
+}
+
+pub(crate) async fn form_fields(
+    State(state): State<AppState>,
+    cookies: Cookies,
+    multipart: Result<Multipart, MultipartRejection>,
+) -> Result<Response, AppError> {
+    let _username = state.require_auth(&cookies)?;
+
+    let mut multipart = multipart_or_bad_request(multipart)?;
+
+    let tmp = TempDir::new().map_err(|e| AppError::Internal(e.to_string()))?;
+    let upload = read_single_upload(&mut multipart, &tmp, false, state.process_timeout).await?;
+    upload.reject_unknown_fields(&[])?;
+
+    let fields =
+        crate::pdf::qpdf_form_fields_with_timeout(&upload.path, state.process_timeout).await?;
+    info!(fields = fields.len(), file = %upload.file_name, "listed form fields");
+    Ok(Json(fields).into_response())
+}
+
+pub(crate) async fn fill_form(
+    State(state): State<AppState>,
+    cookies: Cookies,
+    multipart: Result<Multipart, MultipartRejection>,
+) -> Result<Response, AppError> {
+    let _username = state.require_auth(&cookies)?;
+
+    let mut multipart = multipart_or_bad_request(multipart)?;
+
+    let tmp = TempDir::new().map_err(|e| AppError::Internal(e.to_string()))?;
+    let upload = read_single_upload(&mut multipart, &tmp, false, state.process_timeout).await?;
+    upload.reject_unknown_fields(&["values", "flatten"])?;
+
+    let Some(values) = upload.fields.get("values") else {
+        return Err(AppError::BadRequest("Missing values".to_string()));
+    };
+    let values: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_str(values).map_err(|_| {
+            AppError::BadRequest(
+                "Invalid values (expected a JSON object of field names to values)".to_string(),
+            )
+        })?;
+    let flatten = upload
+        .fields
+        .get("flatten")
+        .is_some_and(|v| parse_bool_loose(v));
+
+    let output_path = crate::pdf::fill_form_with_timeout(
+        &tmp,
+        &upload.path,
+        &values,
+        flatten,
+        state.process_timeout,
+    )
+    .await?;
+    info!(
+        fields = values.len(),
+        flatten,
+        file = %upload.file_name,
+        "filled form"
+    );
+
+    stream_file_response(
+        tmp,
+        output_path,
+        "application/pdf",
+        "attachment; filename=\"filled.pdf\"",
+    )
+    .await
+}
+
+// Sorted, de-duplicated page numbers from an optional range spec (all pages when
+// absent).
+fn selected_pages(spec: Option<&str>, total: usize) -> Result<Vec<usize>, AppError> {
+    let ranges = match spec.map(str::trim).filter(|s| !s.is_empty()) {
+        Some(spec) => parse_page_ranges(spec, total)?,
+        None => vec![PageRange {
+            start: 1,
+            end: total,
+        }],
+    };
+    let pages: BTreeSet<usize> = ranges.iter().flat_map(|r| r.start..=r.end).collect();
+    Ok(pages.into_iter().collect())
+}
+
+// Renders the blank and separator entries of the layout, keyed by layout index.
+async fn generate_layout_pages(
+    tmp: &TempDir,
+    layout: &[LayoutEntry],
+    inputs_by_id: &HashMap<String, PathBuf>,
+    titles_by_id: &HashMap<String, String>,
+    names_by_id: &HashMap<String, String>,
+    process_timeout: Duration,
+) -> Result<HashMap<usize, PathBuf>, AppError> {
+    let mut generated: HashMap<usize, PathBuf> = HashMap::new();
+    let mut geometry_by_doc: HashMap<&str, Vec<PageGeometry>> = HashMap::new();
+    for (idx, entry) in layout.iter().enumerate() {
+        let size = match entry {
+            LayoutEntry::Page(_) => continue,
+            LayoutEntry::Blank(b) => parse_layout_page_size(b.size.as_deref())?,
+            LayoutEntry::Separator(sep) => parse_layout_page_size(sep.size.as_deref())?,
+        };
+        let (width, height) = match size {
+            Some(size) => size,
+            None => {
+                // Nearest document page, looking back first.
+                let Some(r) = layout[..idx]
+                    .iter()
+                    .rev()
+                    .chain(&layout[idx + 1..])
+                    .find_map(LayoutEntry::page_ref)
+                else {
+                    return Err(AppError::BadRequest(
+                        "Layout has no document pages".to_string(),
+                    ));
+                };
+                if !geometry_by_doc.contains_key(r.doc.as_str()) {
+                    let geometry = crate::pdf::qpdf_page_geometry_with_timeout(
+                        &inputs_by_id[&r.doc],
+                        process_timeout,
+                    )
+                    .await?;
+                    geometry_by_doc.insert(r.doc.as_str(), geometry);
+                }
+                let page = geometry_by_doc[r.doc.as_str()]
+                    .get(r.page - 1)
+                    .ok_or_else(|| AppError::Internal(format!("Missing page {}", r.page)))?;
+                let rotate = match r.rotate.as_ref().and_then(|spec| spec.to_rotation()) {
+                    Some(rotation) if rotation.relative => page.rotate + rotation.degrees,
+                    Some(rotation) => rotation.degrees,
+                    None => page.rotate,
+                };
+                page.size_with_rotate(rotate)
+            }
+        };
+
+        let path = match entry {
+            LayoutEntry::Separator(sep) => {
+                let title = match &sep.title {
+                    Some(title) => title.clone(),
+                    None => layout[idx..]
+                        .iter()
+                        .find_map(LayoutEntry::page_ref)
+                        .map(|r| doc_title(&r.doc, titles_by_id, names_by_id))
+                        .unwrap_or_default(),
+                };
+                crate::pdf::ghostscript_separator_page_with_timeout(
+                    tmp,
+                    width,
+                    height,
+                    &title,
+                    process_timeout,
+                )
                 .await?
-        } else {
-            merged_path
+            }
+            _ => {
+                crate::pdf::ghostscript_blank_page_with_timeout(tmp, width, height, process_timeout)
+                    .await?
+            }
+        };
+        generated.insert(idx, path);
+    }
+    Ok(generated)
+}
+
+fn doc_title(
+    doc: &str,
+    titles_by_id: &HashMap<String, String>,
+    names_by_id: &HashMap<String, String>,
+) -> String {
+    titles_by_id
+        .get(doc)
+        .map(|t| t.trim())
+        .filter(|t| !t.is_empty())
+        .or_else(|| names_by_id.get(doc).map(String::as_str))
+        .unwrap_or(doc)
+        .to_string()
+}
+
+fn encryption_options(
+    user_password: Option<String>,
+    owner_password: Option<String>,
+    permissions: Option<OutputPermissions>,
+) -> Result<Option<EncryptionOptions>, AppError> {
+    let user_password = user_password.unwrap_or_default();
+    let owner_password = owner_password.unwrap_or_default();
+    if user_password.is_empty() && owner_password.is_empty() && permissions.is_none() {
+        return Ok(None);
+    }
+    if user_password.contains(['\r', '\n']) || owner_password.contains(['\r', '\n']) {
+        return Err(AppError::BadRequest(
+            "Passwords must not contain line breaks".to_string(),
+        ));
+    }
+
+    // Without an owner password anyone could lift the restrictions, so fall back
+    // to a random one nobody knows.
+    let owner_password = if owner_password.is_empty() {
+        uuid::Uuid::new_v4().simple().to_string()
+    } else {
+        owner_password
+    };
+
+    Ok(Some(EncryptionOptions {
+        user_password,
+        owner_password,
+        permissions: permissions.unwrap_or_default(),
+    }))
+}
+
+struct SingleUpload {
+    path: PathBuf,
+    // The `password` field; `path` is already unlocked with it unless the
+    // upload was read with `read_single_upload_encrypted`.
+    password: Option<String>,
+    file_name: String,
+    fields: HashMap<String, String>,
+}
+
+impl SingleUpload {
+    fn reject_unknown_fields(&self, allowed: &[&str]) -> Result<(), AppError> {
+        match self.fields.keys().find(|k| !allowed.contains(&k.as_str())) {
+            Some(name) => Err(AppError::BadRequest(format!(
+                "Unexpected form field: {name}"
+            ))),
+            None => Ok(()),
         }
+    }
+}
+
+// Reads a form with a single `file` part plus text fields, and unlocks the PDF
+// with the optional `password` field. With `allow_images`, an image upload is
+// converted to PDF with default options.
+async fn read_single_upload(
+    multipart: &mut Multipart,
+    tmp: &TempDir,
+    allow_images: bool,
+    process_timeout: Duration,
+) -> Result<SingleUpload, AppError> {
+    let mut upload =
+        read_single_upload_encrypted(multipart, tmp, allow_images, process_timeout).await?;
+    upload.path = unlock_input(
+        tmp,
+        upload.path,
+        upload.password.as_deref(),
+        &upload.file_name,
+        Some("password"),
+        process_timeout,
+    )
+    .await?;
+    Ok(upload)
+}
+
+// Like `read_single_upload`, but leaves the PDF as uploaded.
+async fn read_single_upload_encrypted(
+    multipart: &mut Multipart,
+    tmp: &TempDir,
+    allow_images: bool,
+    process_timeout: Duration,
+) -> Result<SingleUpload, AppError> {
+    let mut pdf_path: Option<PathBuf> = None;
+    let mut file_name: Option<String> = None;
+    let mut fields: HashMap<String, String> = HashMap::new();
+
+    while let Some(mut field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| AppError::BadRequest(e.to_string()))?
+    {
+        let name = field.name().unwrap_or("").to_string();
+        if name != "file" {
+            let value = field_text(field).await?;
+            fields.insert(name, value);
+            continue;
+        }
+        if pdf_path.is_some() {
+            return Err(AppError::BadRequest(
+                "Only one file can be processed at a time".to_string(),
+            ));
+        }
+
+        let f_name = field.file_name().unwrap_or("file.pdf").to_string();
+        ensure_upload_content_type(&field, &f_name, allow_images)?;
+
+        let path = tmp.path().join("in.pdf");
+        let kind = save_upload_field(&mut field, &path, &f_name, allow_images).await?;
+        let path = match kind {
+            UploadKind::Pdf => path,
+            UploadKind::Image => {
+                crate::pdf::img2pdf_convert_with_timeout(
+                    tmp,
+                    &path,
+                    &f_name,
+                    &ImageToPdfOptions::default(),
+                    process_timeout,
+                )
+                .await?
+            }
+        };
+
+        pdf_path = Some(path);
+        file_name = Some(f_name);
+    }
+
+    let Some(path) = pdf_path else {
+        return Err(AppError::BadRequest("Missing file".to_string()));
     };
+    let file_name = file_name.unwrap_or_else(|| "file.pdf".to_string());
+
+    let password = fields.remove("password");
+
+    Ok(SingleUpload {
+        path,
+        password,
+        file_name,
+        fields,
+    })
+}
+
+fn multipart_or_bad_request(
+    multipart: Result<Multipart, MultipartRejection>,
+) -> Result<Multipart, AppError> {
+    multipart.map_err(|e| {
+        error!(error = %e, "multipart parse failed");
+        AppError::BadRequest("Error parsing multipart/form-data request".to_string())
+    })
+}
+
+async fn field_text(field: Field<'_>) -> Result<String, AppError> {
+    field
+        .text()
+        .await
+        .map_err(|e| AppError::BadRequest(e.to_string()))
+}
+
+const IMAGE_CONTENT_TYPES: [&str; 3] = ["image/jpeg", "image/png", "image/tiff"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum UploadKind {
+    Pdf,
+    Image,
+}
+
+fn ensure_upload_content_type(
+    field: &Field<'_>,
+    file_name: &str,
+    allow_images: bool,
+) -> Result<(), AppError> {
+    let content_type = field
+        .content_type()
+        .map(|m| m.split(';').next().unwrap_or("").trim().to_string())
+        .unwrap_or_default();
+
+    if content_type.is_empty() || content_type == mime::APPLICATION_PDF.essence_str() {
+        return Ok(());
+    }
+    if allow_images {
+        if IMAGE_CONTENT_TYPES.contains(&content_type.as_str()) {
+            return Ok(());
+        }
+        return Err(AppError::BadRequest(format!(
+            "Only PDF, JPEG, PNG and TIFF files are allowed (got {content_type} for {file_name})"
+        )));
+    }
+    Err(AppError::BadRequest(format!(
+        "Only PDF files are allowed (got {content_type} for {file_name})"
+    )))
+}
 
+async fn save_upload_field(
+    field: &mut Field<'_>,
+    path: &Path,
+    file_name: &str,
+    allow_images: bool,
+) -> Result<UploadKind, AppError> {
+    let written = write_multipart_field_to_file(field, path).await?;
+    if written > MAX_FILE_BYTES {
+        return Err(AppError::BadRequest(format!(
+            "{file_name} is too large (max {} MB)",
+            MAX_FILE_BYTES / 1024 / 1024
+        )));
+    }
+
+    if looks_like_pdf(path).await? {
+        return Ok(UploadKind::Pdf);
+    }
+    if allow_images && looks_like_image(path).await? {
+        return Ok(UploadKind::Image);
+    }
+    Err(AppError::BadRequest(if allow_images {
+        format!("{file_name} does not look like a PDF or a JPEG, PNG or TIFF image")
+    } else {
+        format!("{file_name} does not look like a PDF")
+    }))
+}
+
+async fn unlock_input(
+    tmp: &TempDir,
+    path: PathBuf,
+    password: Option<&str>,
+    file_name: &str,
+    password_field: Option<&str>,
+    process_timeout: Duration,
+) -> Result<PathBuf, AppError> {
+    let password = input_password(password)?;
+    match qpdf_check_encryption_with_timeout(tmp, &path, password, process_timeout).await? {
+        InputEncryption::None => Ok(path),
+        InputEncryption::Unlocked => {
+            qpdf_decrypt_file_with_timeout(tmp, &path, password, process_timeout).await
+        }
+        InputEncryption::PasswordRequired if password.is_some() => Err(AppError::Unprocessable(
+            format!("Wrong password for {file_name}"),
+        )),
+        InputEncryption::PasswordRequired => Err(AppError::Unprocessable(match password_field {
+            Some(field) => {
+                format!("{file_name} is password-protected (send its password as {field})")
+            }
+            None => format!("{file_name} is password-protected"),
+        })),
+    }
+}
+
+// Empty passwords count as none. qpdf reads passwords from a file, line by line.
+fn input_password(password: Option<&str>) -> Result<Option<&str>, AppError> {
+    let password = password.filter(|p| !p.is_empty());
+    if password.is_some_and(|p| p.contains(['\r', '\n'])) {
+        return Err(AppError::BadRequest(
+            "Passwords must not contain line breaks".to_string(),
+        ));
+    }
+    Ok(password)
+}
+
+fn archive_safe_stem(file_name: &str) -> String {
+    let stem = Path::new(file_name)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("");
+    let stem: String = stem
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if stem.is_empty() {
+        "file".to_string()
+    } else {
+        stem
+    }
+}
+
+// JSON with non-ASCII characters escaped, so it fits in a header value.
+fn ascii_json(value: &impl Serialize) -> Result<String, AppError> {
+    let json = serde_json::to_string(value).map_err(|e| AppError::Internal(e.to_string()))?;
+    let mut out = String::with_capacity(json.len());
+    for c in json.chars() {
+        if c.is_ascii() {
+            out.push(c);
+        } else {
+            let mut units = [0u16; 2];
+            for unit in c.encode_utf16(&mut units) {
+                let _ = write!(out, "\\u{unit:04x}");
+            }
+        }
+    }
+    Ok(out)
+}
+
+async fn stream_file_response(
+    tmp: TempDir,
+    output_path: PathBuf,
+    content_type: &'static str,
+    content_disposition: &'static str,
+) -> Result<Response, AppError> {
     let meta = tokio::fs::metadata(&output_path)
         .await
         .map_err(|e| AppError::Internal(e.to_string()))?;
@@ -332,13 +2104,11 @@ pub(crate) async fn merge(
 
     let body = Body::from_stream(ReceiverStream::new(rx));
     let mut res = Response::new(body);
-    res.headers_mut().insert(
-        header::CONTENT_TYPE,
-        HeaderValue::from_static("application/pdf"),
-    );
+    res.headers_mut()
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
     res.headers_mut().insert(
         header::CONTENT_DISPOSITION,
-        HeaderValue::from_static("attachment; filename=\"merged.pdf\""),
+        HeaderValue::from_static(content_disposition),
     );
     res.headers_mut().insert(
         header::CONTENT_LENGTH,
@@ -0,0 +1,353 @@
+use std::collections::HashMap;
+
+use crate::error::AppError;
+use crate::util::{paper_size_points, parse_number};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ImpositionMode {
+    Nup(usize),
+    Booklet,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ReadingOrder {
+    LeftToRight,
+    RightToLeft,
+    TopToBottom,
+}
+
+impl ReadingOrder {
+    pub(crate) fn parse(s: &str) -> Result<Self, AppError> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "ltr" => Ok(Self::LeftToRight),
+            "rtl" => Ok(Self::RightToLeft),
+            "ttb" => Ok(Self::TopToBottom),
+            other => Err(AppError::BadRequest(format!(
+                "Invalid reading order: {other} (expected ltr, rtl or ttb)"
+            ))),
+        }
+    }
+}
+
+pub(crate) struct ImpositionOptions {
+    pub(crate) mode: ImpositionMode,
+    pub(crate) sheet_width: f64,
+    pub(crate) sheet_height: f64,
+    pub(crate) gutter: f64,
+    pub(crate) order: ReadingOrder,
+}
+
+impl ImpositionOptions {
+    // Builds the options from the `imposition*` multipart fields; `None` when no
+    // imposition was requested.
+    pub(crate) fn from_fields(fields: &HashMap<String, String>) -> Result<Option<Self>, AppError> {
+        let mode = fields
+            .get("imposition")
+            .map(|v| v.trim().to_ascii_lowercase())
+            .unwrap_or_default();
+        if mode.is_empty() || mode == "none" {
+            if let Some(name) = fields.keys().find(|k| k.as_str() != "imposition") {
+                return Err(AppError::BadRequest(format!("{name} requires imposition")));
+            }
+            return Ok(None);
+        }
+        if let Some(name) = fields.keys().find(|k| {
+            !matches!(
+                k.as_str(),
+                "imposition"
+                    | "imposition_sheet_size"
+                    | "imposition_gutter"
+                    | "imposition_reading_order"
+            )
+        }) {
+            return Err(AppError::BadRequest(format!(
+                "Unexpected form field: {name}"
+            )));
+        }
+
+        let mode = match mode.as_str() {
+            "booklet" => ImpositionMode::Booklet,
+            other => match other.trim_end_matches("-up").parse::<usize>() {
+                Ok(n @ (2 | 4 | 6 | 8 | 9 | 16)) => ImpositionMode::Nup(n),
+                _ => {
+                    return Err(AppError::BadRequest(format!(
+                        "Invalid imposition: {other} (expected 2-up, 4-up, 6-up, 8-up, 9-up, 16-up or booklet)"
+                    )))
+                }
+            },
+        };
+        let (width, height) = match fields.get("imposition_sheet_size") {
+            Some(v) => paper_size_points(v).ok_or_else(|| {
+                AppError::BadRequest(format!(
+                    "Invalid imposition_sheet_size: {v} (expected a3, a4, a5, letter, legal, tabloid or WxH in points)"
+                ))
+            })?,
+            None => (595.0, 842.0),
+        };
+        let gutter = parse_number(fields, "imposition_gutter", 0.0, 0.0..=144.0)?;
+        let order = match fields.get("imposition_reading_order") {
+            Some(v) => ReadingOrder::parse(v)?,
+            None => ReadingOrder::LeftToRight,
+        };
+        if mode == ImpositionMode::Booklet && order == ReadingOrder::TopToBottom {
+            return Err(AppError::BadRequest(
+                "Booklets support only ltr or rtl reading order".to_string(),
+            ));
+        }
+
+        // Grids wider than tall go on a landscape sheet.
+        let (cols, rows) = grid(mode);
+        let (sheet_width, sheet_height) = if cols > rows {
+            (width.max(height), width.min(height))
+        } else {
+            (width.min(height), width.max(height))
+        };
+        let options = Self {
+            mode,
+            sheet_width,
+            sheet_height,
+            gutter,
+            order,
+        };
+        let (cell_width, cell_height) = options.cell_size();
+        if gutter >= cell_width.min(cell_height) {
+            return Err(AppError::BadRequest(
+                "imposition_gutter is too large for the sheet".to_string(),
+            ));
+        }
+        Ok(Some(options))
+    }
+
+    pub(crate) fn grid(&self) -> (usize, usize) {
+        grid(self.mode)
+    }
+
+    pub(crate) fn cell_size(&self) -> (f64, f64) {
+        let (cols, rows) = self.grid();
+        (
+            self.sheet_width / cols as f64,
+            self.sheet_height / rows as f64,
+        )
+    }
+
+    // Output sheet side (1-based) on which each source page ends up.
+    pub(crate) fn sheet_of_pages(&self, cells: &[Option<usize>]) -> HashMap<usize, usize> {
+        let (cols, rows) = self.grid();
+        cells
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, page)| page.map(|p| (p, idx / (cols * rows) + 1)))
+            .collect()
+    }
+
+    // Source page (1-based) for every cell, sheet after sheet and row by row from
+    // the top left; `None` marks a blank cell.
+    pub(crate) fn cells(&self, total_pages: usize) -> Vec<Option<usize>> {
+        let (cols, rows) = self.grid();
+        match self.mode {
+            ImpositionMode::Nup(n) => {
+                let sheets = total_pages.div_ceil(n);
+                let mut cells = Vec::with_capacity(sheets * n);
+                for sheet in 0..sheets {
+                    for cell in 0..n {
+                        let (row, col) = (cell / cols, cell % cols);
+                        let ordinal = match self.order {
+                            ReadingOrder::LeftToRight => cell,
+                            ReadingOrder::RightToLeft => row * cols + (cols - 1 - col),
+                            ReadingOrder::TopToBottom => col * rows + row,
+                        };
+                        let page = sheet * n + ordinal + 1;
+                        cells.push((page <= total_pages).then_some(page));
+                    }
+                }
+                cells
+            }
+            ImpositionMode::Booklet => {
+                // Every folded sheet carries four pages: outer pair on the front,
+                // inner pair on the back.
+                let padded = total_pages.div_ceil(4) * 4;
+                let mut cells = Vec::with_capacity(padded);
+                for sheet in 0..padded / 4 {
+                    let front = [padded - 2 * sheet, 2 * sheet + 1];
+                    let back = [2 * sheet + 2, padded - 2 * sheet - 1];
+                    for [left, right] in [front, back] {
+                        let pair = match self.order {
+                            ReadingOrder::RightToLeft => [right, left],
+                            _ => [left, right],
+                        };
+                        cells.extend(pair.map(|page| (page <= total_pages).then_some(page)));
+                    }
+                }
+                cells
+            }
+        }
+    }
+}
+
+fn grid(mode: ImpositionMode) -> (usize, usize) {
+    match mode {
+        ImpositionMode::Nup(2) | ImpositionMode::Booklet => (2, 1),
+        ImpositionMode::Nup(6) => (3, 2),
+        ImpositionMode::Nup(8) => (4, 2),
+        ImpositionMode::Nup(9) => (3, 3),
+        ImpositionMode::Nup(16) => (4, 4),
+        ImpositionMode::Nup(_) => (2, 2),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(mode: ImpositionMode, order: ReadingOrder) -> ImpositionOptions {
+        ImpositionOptions {
+            mode,
+            sheet_width: 842.0,
+            sheet_height: 595.0,
+            gutter: 0.0,
+            order,
+        }
+    }
+
+    fn fields(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn nup_fills_rows_from_the_top_left() {
+        let cells = options(ImpositionMode::Nup(4), ReadingOrder::LeftToRight).cells(5);
+        assert_eq!(
+            cells,
+            vec![
+                Some(1),
+                Some(2),
+                Some(3),
+                Some(4),
+                Some(5),
+                None,
+                None,
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn nup_right_to_left_mirrors_each_row() {
+        let cells = options(ImpositionMode::Nup(4), ReadingOrder::RightToLeft).cells(4);
+        assert_eq!(cells, vec![Some(2), Some(1), Some(4), Some(3)]);
+    }
+
+    #[test]
+    fn nup_top_to_bottom_fills_columns() {
+        let cells = options(ImpositionMode::Nup(6), ReadingOrder::TopToBottom).cells(4);
+        assert_eq!(cells, vec![Some(1), Some(3), None, Some(2), Some(4), None]);
+    }
+
+    #[test]
+    fn booklet_pads_to_a_multiple_of_four() {
+        let cells = options(ImpositionMode::Booklet, ReadingOrder::LeftToRight).cells(6);
+        assert_eq!(
+            cells,
+            vec![
+                None,
+                Some(1),
+                Some(2),
+                None,
+                Some(6),
+                Some(3),
+                Some(4),
+                Some(5)
+            ]
+        );
+
+        let cells = options(ImpositionMode::Booklet, ReadingOrder::LeftToRight).cells(5);
+        assert_eq!(
+            cells,
+            vec![
+                None,
+                Some(1),
+                Some(2),
+                None,
+                None,
+                Some(3),
+                Some(4),
+                Some(5)
+            ]
+        );
+
+        let cells = options(ImpositionMode::Booklet, ReadingOrder::LeftToRight).cells(1);
+        assert_eq!(cells, vec![None, Some(1), None, None]);
+    }
+
+    #[test]
+    fn booklet_right_to_left_swaps_each_pair() {
+        let cells = options(ImpositionMode::Booklet, ReadingOrder::RightToLeft).cells(6);
+        assert_eq!(
+            cells,
+            vec![
+                Some(1),
+                None,
+                None,
+                Some(2),
+                Some(3),
+                Some(6),
+                Some(5),
+                Some(4)
+            ]
+        );
+    }
+
+    #[test]
+    fn maps_pages_to_sheet_sides() {
+        let options = options(ImpositionMode::Booklet, ReadingOrder::LeftToRight);
+        let sheets = options.sheet_of_pages(&options.cells(6));
+        let expected = [(1, 1), (2, 2), (6, 3), (3, 3), (4, 4), (5, 4)];
+        assert_eq!(sheets, expected.into_iter().collect());
+    }
+
+    #[test]
+    fn wide_grids_use_a_landscape_sheet() {
+        let options = ImpositionOptions::from_fields(&fields(&[("imposition", "6-up")]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(options.grid(), (3, 2));
+        assert_eq!((options.sheet_width, options.sheet_height), (842.0, 595.0));
+
+        let options = ImpositionOptions::from_fields(&fields(&[("imposition", "4-up")]))
+            .unwrap()
+            .unwrap();
+        assert_eq!((options.sheet_width, options.sheet_height), (595.0, 842.0));
+    }
+
+    #[test]
+    fn rejects_bad_fields() {
+        for pairs in [
+            vec![("imposition", "3-up")],
+            vec![("imposition_gutter", "10")],
+            vec![
+                ("imposition", "booklet"),
+                ("imposition_reading_order", "ttb"),
+            ],
+            vec![
+                ("imposition", "16-up"),
+                ("imposition_sheet_size", "a5"),
+                ("imposition_gutter", "144"),
+            ],
+            vec![("imposition", "2-up"), ("imposition_scale", "1")],
+        ] {
+            assert!(
+                matches!(
+                    ImpositionOptions::from_fields(&fields(&pairs)),
+                    Err(AppError::BadRequest(_))
+                ),
+                "{pairs:?} should be rejected"
+            );
+        }
+        assert!(ImpositionOptions::from_fields(&fields(&[]))
+            .unwrap()
+            .is_none());
+    }
+}
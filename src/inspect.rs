@@ -0,0 +1,520 @@
+use std::collections::HashSet;
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::error::AppError;
+use crate::geometry::{inherited, page_geometry_from_qpdf_json};
+use crate::qpdf_json::{self, object_dict, resolve};
+
+#[derive(Serialize)]
+pub(crate) struct InspectReport {
+    pub(crate) pages: usize,
+    pub(crate) pdf_version: Option<String>,
+    pub(crate) page_sizes: Vec<PageInfo>,
+    pub(crate) encryption: EncryptionInfo,
+    pub(crate) linearized: bool,
+    pub(crate) tagged: bool,
+    pub(crate) forms: bool,
+    pub(crate) javascript: bool,
+    pub(crate) attachments: bool,
+    pub(crate) outlines: bool,
+    pub(crate) fonts: Vec<FontInfo>,
+    pub(crate) image_count: usize,
+    pub(crate) images: Vec<ImageInfo>,
+}
+
+// What /api/inspect returns for a file that needs a password it didn't get.
+#[derive(Serialize)]
+pub(crate) struct LockedInspectReport {
+    pub(crate) encryption: EncryptionInfo,
+}
+
+#[derive(Serialize)]
+pub(crate) struct PageInfo {
+    pub(crate) page: usize,
+    // Points, as displayed (CropBox, after /Rotate).
+    pub(crate) width: f64,
+    pub(crate) height: f64,
+    pub(crate) rotate: i64,
+    pub(crate) images: usize,
+}
+
+#[derive(Serialize)]
+pub(crate) struct EncryptionInfo {
+    pub(crate) encrypted: bool,
+    pub(crate) password_required: bool,
+    pub(crate) method: Option<String>,
+    pub(crate) bits: Option<u64>,
+    pub(crate) permissions: Option<Permissions>,
+}
+
+impl EncryptionInfo {
+    // qpdf won't read the encryption parameters without the password either.
+    pub(crate) fn password_required() -> Self {
+        Self {
+            encrypted: true,
+            password_required: true,
+            method: None,
+            bits: None,
+            permissions: None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct Permissions {
+    pub(crate) print: bool,
+    pub(crate) print_high_quality: bool,
+    pub(crate) copy: bool,
+    pub(crate) accessibility: bool,
+    pub(crate) modify: bool,
+    pub(crate) annotate: bool,
+    pub(crate) fill_forms: bool,
+    pub(crate) assemble: bool,
+}
+
+#[derive(Serialize, PartialEq)]
+pub(crate) struct FontInfo {
+    pub(crate) name: String,
+    #[serde(rename = "type")]
+    pub(crate) subtype: String,
+    pub(crate) embedded: bool,
+    pub(crate) subset: bool,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ImageInfo {
+    pub(crate) page: usize,
+    pub(crate) width: u64,
+    pub(crate) height: u64,
+    pub(crate) bits_per_component: Option<u64>,
+    pub(crate) color_space: Option<String>,
+    pub(crate) filter: Option<String>,
+    // An estimate, not the effective resolution (that depends on how the
+    // content stream scales the image): the resolution the image would have
+    // stretched over the whole page, so a lower bound for images placed
+    // smaller than that.
+    pub(crate) page_fill_dpi: u64,
+}
+
+// Builds the report from `qpdf --json=2` output with the pages, encrypt,
+// acroform, attachments, outlines and qpdf keys.
+pub(crate) fn inspect_report(json: &Value) -> Result<InspectReport, AppError> {
+    let objects = qpdf_json::objects(json)?;
+    let pages = qpdf_json::pages(json)?;
+    let geometry = page_geometry_from_qpdf_json(json)?;
+    let root = qpdf_json::trailer(objects)
+        .and_then(|t| t.get("/Root"))
+        .and_then(Value::as_str)
+        .and_then(|r| object_dict(objects, r));
+
+    let mut page_sizes = Vec::with_capacity(pages.len());
+    let mut images = Vec::new();
+    for (idx, (page, geometry)) in pages.iter().zip(&geometry).enumerate() {
+        let (width, height) = geometry.size();
+        let page_images = page_images(objects, qpdf_json::page_ref(page)?);
+        for image in &page_images {
+            let number = |key: &str| {
+                image
+                    .get(key)
+                    .map(|v| resolve(objects, v))
+                    .and_then(Value::as_u64)
+            };
+            let (Some(px_w), Some(px_h)) = (number("/Width"), number("/Height")) else {
+                continue;
+            };
+            // Long side against long side, so a scan rotated via /Rotate still
+            // lines up.
+            let (px_long, px_short) = (px_w.max(px_h) as f64, px_w.min(px_h) as f64);
+            let (pt_long, pt_short) = (width.max(height), width.min(height).max(1.0));
+            let dpi = (px_long * 72.0 / pt_long).min(px_short * 72.0 / pt_short);
+            images.push(ImageInfo {
+                page: idx + 1,
+                width: px_w,
+                height: px_h,
+                bits_per_component: number("/BitsPerComponent"),
+                // Array colour spaces ([/ICCBased 5 0 R], [/Indexed ...]) by family.
+                color_space: image
+                    .get("/ColorSpace")
+                    .map(|c| resolve(objects, c))
+                    .and_then(|c| c.as_array().and_then(|a| a.first()).or(Some(c)))
+                    .and_then(Value::as_str)
+                    .filter(|c| c.starts_with('/'))
+                    .map(|c| c[1..].to_string()),
+                filter: image
+                    .get("/Filter")
+                    .map(|f| resolve(objects, f))
+                    .and_then(|f| f.as_array().and_then(|a| a.last()).or(Some(f)))
+                    .and_then(Value::as_str)
+                    .filter(|f| f.starts_with('/'))
+                    .map(|f| f[1..].to_string()),
+                page_fill_dpi: dpi.round() as u64,
+            });
+        }
+        page_sizes.push(PageInfo {
+            page: idx + 1,
+            width: (width * 100.0).round() / 100.0,
+            height: (height * 100.0).round() / 100.0,
+            rotate: geometry.rotate,
+            images: page_images.len(),
+        });
+    }
+
+    let encrypt = json.get("encrypt");
+    let encrypted = encrypt
+        .and_then(|e| e.get("encrypted"))
+        .and_then(Value::as_bool)
+        .unwrap_or(false);
+    let encryption = if encrypted {
+        let params = encrypt.and_then(|e| e.get("parameters"));
+        let capabilities = encrypt.and_then(|e| e.get("capabilities"));
+        let can = |key: &str| {
+            capabilities
+                .and_then(|c| c.get(key))
+                .and_then(Value::as_bool)
+                .unwrap_or(false)
+        };
+        EncryptionInfo {
+            encrypted,
+            password_required: false,
+            method: params
+                .and_then(|p| p.get("method"))
+                .and_then(Value::as_str)
+                .map(str::to_string),
+            bits: params.and_then(|p| p.get("bits")).and_then(Value::as_u64),
+            permissions: Some(Permissions {
+                print: can("printlow"),
+                print_high_quality: can("printhigh"),
+                copy: can("extract"),
+                accessibility: can("accessibility"),
+                modify: can("modifyother"),
+                annotate: can("modifyannotations"),
+                fill_forms: can("modifyforms"),
+                assemble: can("modifyassembly"),
+            }),
+        }
+    } else {
+        EncryptionInfo {
+            encrypted,
+            password_required: false,
+            method: None,
+            bits: None,
+            permissions: None,
+        }
+    };
+
+    let tagged = root
+        .and_then(|r| r.get("/MarkInfo"))
+        .map(|m| resolve(objects, m))
+        .and_then(|m| m.get("/Marked"))
+        .and_then(Value::as_bool)
+        .unwrap_or(false);
+    let forms = json
+        .get("acroform")
+        .and_then(|a| a.get("fields"))
+        .and_then(Value::as_array)
+        .is_some_and(|f| !f.is_empty());
+    let attachments = json
+        .get("attachments")
+        .and_then(Value::as_object)
+        .is_some_and(|a| !a.is_empty());
+    let outlines = json
+        .get("outlines")
+        .and_then(Value::as_array)
+        .is_some_and(|o| !o.is_empty());
+
+    let mut linearized = false;
+    let mut javascript = false;
+    let mut fonts: Vec<FontInfo> = Vec::new();
+    for object in objects.values() {
+        let Some(dict) = object_value(object) else {
+            continue;
+        };
+        linearized |= dict.contains_key("/Linearized");
+        javascript = javascript || dict_has_javascript(dict);
+        if let Some(font) = font_info(objects, dict) {
+            if !fonts.contains(&font) {
+                fonts.push(font);
+            }
+        }
+    }
+    fonts.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(InspectReport {
+        pages: pages.len(),
+        pdf_version: json
+            .get("qpdf")
+            .and_then(|q| q.get(0))
+            .and_then(|h| h.get("pdfversion"))
+            .and_then(Value::as_str)
+            .map(str::to_string),
+        page_sizes,
+        encryption,
+        linearized,
+        tagged,
+        forms,
+        javascript,
+        attachments,
+        outlines,
+        fonts,
+        image_count: images.len(),
+        images,
+    })
+}
+
+// Image XObjects drawn on a page, including those inside Form XObjects, as
+// their stream dictionaries.
+fn page_images<'a>(objects: &'a Map<String, Value>, page_ref: &str) -> Vec<&'a Map<String, Value>> {
+    let mut images = Vec::new();
+    collect_images(
+        objects,
+        inherited(objects, page_ref, "/Resources"),
+        &mut HashSet::new(),
+        &mut images,
+    );
+    images
+}
+
+fn collect_images<'a>(
+    objects: &'a Map<String, Value>,
+    resources: Option<&'a Value>,
+    seen: &mut HashSet<&'a str>,
+    images: &mut Vec<&'a Map<String, Value>>,
+) {
+    let Some(xobjects) = resources
+        .map(|r| resolve(objects, r))
+        .and_then(|r| r.get("/XObject"))
+        .map(|x| resolve(objects, x))
+        .and_then(Value::as_object)
+    else {
+        return;
+    };
+    for xobject in xobjects.values() {
+        // XObjects are streams, so always indirect; `seen` also stops forms
+        // that draw themselves.
+        let Some(obj_ref) = xobject.as_str() else {
+            continue;
+        };
+        if !seen.insert(obj_ref) {
+            continue;
+        }
+        let Some(dict) = objects
+            .get(&format!("obj:{obj_ref}"))
+            .and_then(object_value)
+        else {
+            continue;
+        };
+        match dict.get("/Subtype").and_then(Value::as_str) {
+            Some("/Image") => images.push(dict),
+            Some("/Form") => collect_images(objects, dict.get("/Resources"), seen, images),
+            _ => {}
+        }
+    }
+}
+
+// Dictionary of a plain object or a stream.
+fn object_value(object: &Value) -> Option<&Map<String, Value>> {
+    object
+        .get("value")
+        .or_else(|| object.get("stream").and_then(|s| s.get("dict")))
+        .and_then(Value::as_object)
+}
+
+// JavaScript actions (/S /JavaScript, possibly nested as direct objects) and the
+// document-level /JavaScript name tree.
+fn dict_has_javascript(dict: &Map<String, Value>) -> bool {
+    dict.contains_key("/JS")
+        || dict.contains_key("/JavaScript")
+        || dict.get("/S").and_then(Value::as_str) == Some("/JavaScript")
+        || dict.values().any(|v| match v {
+            Value::Object(d) => dict_has_javascript(d),
+            Value::Array(items) => items
+                .iter()
+                .filter_map(Value::as_object)
+                .any(dict_has_javascript),
+            _ => false,
+        })
+}
+
+fn font_info(objects: &Map<String, Value>, dict: &Map<String, Value>) -> Option<FontInfo> {
+    if dict.get("/Type").and_then(Value::as_str) != Some("/Font") {
+        return None;
+    }
+    let subtype = dict.get("/Subtype").and_then(Value::as_str)?;
+    // CID fonts are reported through the Type0 font using them.
+    if matches!(subtype, "/CIDFontType0" | "/CIDFontType2") {
+        return None;
+    }
+    let name = dict
+        .get("/BaseFont")
+        .and_then(qpdf_json::text)
+        .unwrap_or_else(|| "(unnamed)".to_string());
+
+    let descriptor_owner = if subtype == "/Type0" {
+        dict.get("/DescendantFonts")
+            .map(|d| resolve(objects, d))
+            .and_then(Value::as_array)
+            .and_then(|d| d.first())
+            .map(|d| resolve(objects, d))
+            .and_then(Value::as_object)
+    } else {
+        Some(dict)
+    };
+    let embedded = subtype == "/Type3"
+        || descriptor_owner
+            .and_then(|d| d.get("/FontDescriptor"))
+            .map(|d| resolve(objects, d))
+            .and_then(Value::as_object)
+            .is_some_and(|d| {
+                ["/FontFile", "/FontFile2", "/FontFile3"]
+                    .iter()
+                    .any(|k| d.contains_key(*k))
+            });
+    // Subset fonts are named with a six-letter tag, e.g. ABCDEF+Helvetica.
+    let subset = name
+        .split_once('+')
+        .is_some_and(|(tag, _)| tag.len() == 6 && tag.bytes().all(|b| b.is_ascii_uppercase()));
+
+    Some(FontInfo {
+        name,
+        subtype: subtype.trim_start_matches('/').to_string(),
+        embedded,
+        subset,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    // A letter page inheriting its resources from the page tree: one scan
+    // placed directly and one inside a Form XObject, two fonts and a
+    // JavaScript open action.
+    fn document() -> Value {
+        json!({
+            "qpdf": [
+                {"jsonversion": 2, "pdfversion": "1.6", "maxobjectid": 12},
+                {
+                    "obj:1 0 R": {"value": {
+                        "/Type": "/Catalog",
+                        "/Pages": "2 0 R",
+                        "/MarkInfo": {"/Marked": true},
+                        "/OpenAction": {"/S": "/JavaScript", "/JS": "u:app.alert(1)"}
+                    }},
+                    "obj:2 0 R": {"value": {
+                        "/Type": "/Pages",
+                        "/Kids": ["3 0 R"],
+                        "/Count": 1,
+                        "/MediaBox": [0, 0, 612, 792],
+                        "/Resources": {
+                            "/XObject": {"/Im0": "4 0 R", "/Fm0": "5 0 R"},
+                            "/Font": {"/F0": "7 0 R", "/F1": "8 0 R"}
+                        }
+                    }},
+                    "obj:3 0 R": {"value": {"/Type": "/Page", "/Parent": "2 0 R"}},
+                    "obj:4 0 R": {"stream": {"dict": {
+                        "/Type": "/XObject", "/Subtype": "/Image",
+                        "/Width": 1275, "/Height": 1650, "/BitsPerComponent": 8,
+                        "/ColorSpace": "/DeviceRGB", "/Filter": ["/FlateDecode", "/DCTDecode"]
+                    }}},
+                    "obj:5 0 R": {"stream": {"dict": {
+                        "/Type": "/XObject", "/Subtype": "/Form",
+                        "/Resources": {"/XObject": {"/Im1": "6 0 R", "/Fm0": "5 0 R"}}
+                    }}},
+                    "obj:6 0 R": {"stream": {"dict": {
+                        "/Type": "/XObject", "/Subtype": "/Image",
+                        "/Width": 2550, "/Height": 3300, "/BitsPerComponent": 1,
+                        "/ColorSpace": ["/ICCBased", "9 0 R"], "/Filter": "/CCITTFaxDecode"
+                    }}},
+                    "obj:7 0 R": {"value": {
+                        "/Type": "/Font", "/Subtype": "/TrueType",
+                        "/BaseFont": "/ABCDEF+Arial", "/FontDescriptor": "10 0 R"
+                    }},
+                    "obj:8 0 R": {"value": {
+                        "/Type": "/Font", "/Subtype": "/Type1", "/BaseFont": "/Helvetica"
+                    }},
+                    "obj:9 0 R": {"stream": {"dict": {"/N": 1}}},
+                    "obj:10 0 R": {"value": {"/Type": "/FontDescriptor", "/FontFile2": "11 0 R"}},
+                    "obj:11 0 R": {"stream": {"dict": {}}},
+                    "trailer": {"value": {"/Root": "1 0 R"}}
+                }
+            ],
+            "pages": [{"object": "3 0 R"}],
+            "encrypt": {"encrypted": false},
+            "acroform": {"fields": []},
+            "attachments": {},
+            "outlines": []
+        })
+    }
+
+    #[test]
+    fn reports_pages_and_document_flags() {
+        let report = inspect_report(&document()).unwrap();
+        assert_eq!(report.pages, 1);
+        assert_eq!(report.pdf_version.as_deref(), Some("1.6"));
+        assert_eq!(
+            (report.page_sizes[0].width, report.page_sizes[0].height),
+            (612.0, 792.0)
+        );
+        assert!(report.tagged && report.javascript);
+        assert!(!report.forms && !report.attachments && !report.outlines && !report.linearized);
+        assert!(!report.encryption.encrypted && report.encryption.permissions.is_none());
+    }
+
+    #[test]
+    fn finds_images_inside_form_xobjects() {
+        let report = inspect_report(&document()).unwrap();
+        assert_eq!(report.image_count, 2);
+        assert_eq!(report.page_sizes[0].images, 2);
+        let summary: Vec<_> = report
+            .images
+            .iter()
+            .map(|i| {
+                (
+                    i.width,
+                    i.color_space.as_deref(),
+                    i.filter.as_deref(),
+                    i.page_fill_dpi,
+                )
+            })
+            .collect();
+        assert!(summary.contains(&(1275, Some("DeviceRGB"), Some("DCTDecode"), 150)));
+        assert!(summary.contains(&(2550, Some("ICCBased"), Some("CCITTFaxDecode"), 300)));
+    }
+
+    #[test]
+    fn reports_fonts_with_embedding_and_subsets() {
+        let report = inspect_report(&document()).unwrap();
+        let fonts: Vec<_> = report
+            .fonts
+            .iter()
+            .map(|f| (f.name.as_str(), f.subtype.as_str(), f.embedded, f.subset))
+            .collect();
+        assert_eq!(
+            fonts,
+            vec![
+                ("ABCDEF+Arial", "TrueType", true, true),
+                ("Helvetica", "Type1", false, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn maps_encryption_capabilities() {
+        let mut json = document();
+        json["encrypt"] = json!({
+            "encrypted": true,
+            "parameters": {"method": "AESv3", "bits": 256},
+            "capabilities": {"printlow": true, "printhigh": false, "extract": false}
+        });
+        let encryption = inspect_report(&json).unwrap().encryption;
+        assert_eq!(encryption.method.as_deref(), Some("AESv3"));
+        assert_eq!(encryption.bits, Some(256));
+        assert!(!encryption.password_required);
+        let permissions = encryption.permissions.unwrap();
+        assert!(permissions.print && !permissions.print_high_quality && !permissions.copy);
+    }
+}
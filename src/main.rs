@@ -4,14 +4,24 @@ use time::Duration;
 use tracing::info;
 
 mod app;
+mod archive;
 mod config;
 mod constants;
 mod error;
+mod forms;
+mod geometry;
 mod handlers;
+mod impose;
+mod inspect;
+mod metadata;
+mod outline;
 mod pages;
 mod pdf;
+mod qpdf_json;
+mod ranges;
 mod session;
 mod shutdown;
+mod stamp;
 mod state;
 mod util;
 
@@ -34,6 +44,8 @@ async fn main() {
         config.process_timeout,
         config.cookie_secure,
         config.trust_proxy_headers,
+        config.pdfa_icc_profile,
+        config.verapdf_path,
     );
 
     let app = app::build_router(state);
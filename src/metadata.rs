@@ -0,0 +1,144 @@
+use std::fmt::Write;
+
+use serde_json::{Map, Value};
+
+use crate::error::AppError;
+use crate::qpdf_json::{self, object_dict};
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct DocumentMetadata {
+    #[serde(default)]
+    pub(crate) title: Option<String>,
+    #[serde(default)]
+    pub(crate) author: Option<String>,
+    #[serde(default)]
+    pub(crate) subject: Option<String>,
+    #[serde(default)]
+    pub(crate) keywords: Option<String>,
+    #[serde(default)]
+    pub(crate) creator: Option<String>,
+    #[serde(default)]
+    pub(crate) producer: Option<String>,
+    #[serde(default)]
+    pub(crate) strip: bool,
+}
+
+impl DocumentMetadata {
+    pub(crate) fn parse(json: &str) -> Result<Self, AppError> {
+        let meta: Self = serde_json::from_str(json)
+            .map_err(|e| AppError::BadRequest(format!("Invalid metadata: {e}")))?;
+        for (key, value) in meta.entries() {
+            if value.is_some_and(|v| v.chars().count() > 1024) {
+                return Err(AppError::BadRequest(format!(
+                    "Metadata {key} is too long (max 1024 characters)"
+                )));
+            }
+        }
+        Ok(meta)
+    }
+
+    fn entries(&self) -> [(&'static str, Option<&str>); 6] {
+        [
+            ("Title", self.title.as_deref()),
+            ("Author", self.author.as_deref()),
+            ("Subject", self.subject.as_deref()),
+            ("Keywords", self.keywords.as_deref()),
+            ("Creator", self.creator.as_deref()),
+            ("Producer", self.producer.as_deref()),
+        ]
+    }
+
+    // DOCINFO pdfmark; pdfwrite mirrors the Info dictionary into the XMP packet it
+    // writes. With `strip` set, keys that weren't given are blanked so Ghostscript
+    // doesn't fill in its own (the inputs' metadata is removed by `strip_update`).
+    pub(crate) fn to_pdfmark(&self) -> String {
+        let mut out = String::from("[");
+        for (key, value) in self.entries() {
+            match value {
+                Some(v) => {
+                    let _ = write!(out, " /{key} {}", pdf_text_string(v));
+                }
+                None if self.strip => {
+                    let _ = write!(out, " /{key} ()");
+                }
+                None => {}
+            }
+        }
+        out.push_str(" /DOCINFO pdfmark");
+        out
+    }
+}
+
+// `--update-from-json` document dropping the Info dictionary (custom keys and
+// dates included) and the catalog's XMP /Metadata stream.
+pub(crate) fn strip_update(json: &Value) -> Result<Value, AppError> {
+    let objects = qpdf_json::objects(json)?;
+    let mut trailer = qpdf_json::trailer(objects)
+        .cloned()
+        .ok_or_else(|| qpdf_json::invalid("missing trailer"))?;
+    let root_ref = trailer
+        .get("/Root")
+        .and_then(Value::as_str)
+        .ok_or_else(|| qpdf_json::invalid("missing document root"))?
+        .to_string();
+    let mut root = object_dict(objects, &root_ref)
+        .cloned()
+        .ok_or_else(|| qpdf_json::invalid("missing document root"))?;
+    trailer.remove("/Info");
+    root.remove("/Metadata");
+
+    let mut updates = Map::new();
+    updates.insert("trailer".to_string(), Value::Object(trailer));
+    updates.insert(format!("obj:{root_ref}"), Value::Object(root));
+    qpdf_json::update_document(json, updates)
+}
+
+// PDF text string: a literal for printable ASCII, UTF-16BE with BOM otherwise.
+pub(crate) fn pdf_text_string(s: &str) -> String {
+    if s.chars().all(|c| (' '..='~').contains(&c)) {
+        let mut out = String::from("(");
+        for c in s.chars() {
+            if matches!(c, '(' | ')' | '\\') {
+                out.push('\\');
+            }
+            out.push(c);
+        }
+        out.push(')');
+        return out;
+    }
+
+    let mut out = String::from("<FEFF");
+    for unit in s.encode_utf16() {
+        let _ = write!(out, "{unit:04X}");
+    }
+    out.push('>');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ascii_text_is_a_literal_string() {
+        assert_eq!(pdf_text_string(r"Q1 (draft) \ v2"), r"(Q1 \(draft\) \\ v2)");
+        assert_eq!(pdf_text_string(""), "()");
+    }
+
+    #[test]
+    fn other_text_is_utf16be_with_bom() {
+        assert_eq!(pdf_text_string("Grüße"), "<FEFF0047007200FC00DF0065>");
+        assert_eq!(pdf_text_string("(é)"), "<FEFF002800E90029>");
+    }
+
+    #[test]
+    fn non_bmp_text_uses_surrogate_pairs() {
+        assert_eq!(pdf_text_string("a😀"), "<FEFF0061D83DDE00>");
+    }
+
+    #[test]
+    fn control_characters_are_not_literal() {
+        assert_eq!(pdf_text_string("a\nb"), "<FEFF0061000A0062>");
+    }
+}
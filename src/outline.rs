@@ -0,0 +1,165 @@
+use std::fmt::Write;
+
+use crate::metadata::pdf_text_string;
+
+pub(crate) struct OutlineEntry {
+    pub(crate) title: String,
+    pub(crate) page: usize,
+    pub(crate) children: Vec<OutlineEntry>,
+}
+
+// Outline item as reported by `qpdf --json=2 --json-key=outlines`.
+#[derive(serde::Deserialize)]
+pub(crate) struct SourceOutlineItem {
+    pub(crate) title: String,
+    #[serde(rename = "destpageposfrom1", default)]
+    pub(crate) page: Option<usize>,
+    #[serde(default)]
+    pub(crate) kids: Vec<SourceOutlineItem>,
+}
+
+// Moves already-built entries to new output pages, e.g. after imposition.
+pub(crate) fn move_outline_pages(entries: &mut [OutlineEntry], page_map: &impl Fn(usize) -> usize) {
+    for entry in entries {
+        entry.page = page_map(entry.page);
+        move_outline_pages(&mut entry.children, page_map);
+    }
+}
+
+// Maps a source outline onto output pages. Items whose target didn't make it into
+// the output are dropped and their surviving children move up a level.
+pub(crate) fn remap_outline(
+    items: &[SourceOutlineItem],
+    page_map: &impl Fn(usize) -> Option<usize>,
+) -> Vec<OutlineEntry> {
+    let mut out = Vec::new();
+    for item in items {
+        let children = remap_outline(&item.kids, page_map);
+        match item.page.and_then(page_map) {
+            Some(page) => out.push(OutlineEntry {
+                title: item.title.clone(),
+                page,
+                children,
+            }),
+            None => out.extend(children),
+        }
+    }
+    out
+}
+
+// One /OUT pdfmark per entry, parents first with /Count announcing how many
+// direct children follow.
+pub(crate) fn outline_pdfmarks(entries: &[OutlineEntry]) -> String {
+    let mut out = String::new();
+    for entry in entries {
+        write_entry(&mut out, entry);
+    }
+    out
+}
+
+fn write_entry(out: &mut String, entry: &OutlineEntry) {
+    let _ = write!(out, "[");
+    if !entry.children.is_empty() {
+        let _ = write!(out, " /Count {}", entry.children.len());
+    }
+    let _ = writeln!(
+        out,
+        " /Title {} /Page {} /View [/XYZ null null null] /OUT pdfmark",
+        pdf_text_string(&entry.title),
+        entry.page
+    );
+    for child in &entry.children {
+        write_entry(out, child);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    // Source outline: Intro (p1) > [Scope (p2)], Appendix (p5) > [Table (p6)],
+    // plus a heading without a destination page.
+    fn source() -> Vec<SourceOutlineItem> {
+        serde_json::from_value(json!([
+            {"title": "Intro", "destpageposfrom1": 1, "kids": [
+                {"title": "Scope", "destpageposfrom1": 2, "kids": []}
+            ]},
+            {"title": "Appendix", "destpageposfrom1": 5, "kids": [
+                {"title": "Table", "destpageposfrom1": 6, "kids": []}
+            ]},
+            {"title": "Notes", "destpageposfrom1": null, "kids": []}
+        ]))
+        .unwrap()
+    }
+
+    fn flatten(entries: &[OutlineEntry], depth: usize, out: &mut Vec<(usize, String, usize)>) {
+        for entry in entries {
+            out.push((depth, entry.title.clone(), entry.page));
+            flatten(&entry.children, depth + 1, out);
+        }
+    }
+
+    fn flat(entries: &[OutlineEntry]) -> Vec<(usize, String, usize)> {
+        let mut out = Vec::new();
+        flatten(entries, 0, &mut out);
+        out
+    }
+
+    #[test]
+    fn remaps_pages_through_the_layout() {
+        // Source pages land after a 10-page document.
+        let entries = remap_outline(&source(), &|p| Some(p + 10));
+        assert_eq!(
+            flat(&entries),
+            vec![
+                (0, "Intro".to_string(), 11),
+                (1, "Scope".to_string(), 12),
+                (0, "Appendix".to_string(), 15),
+                (1, "Table".to_string(), 16),
+            ]
+        );
+    }
+
+    #[test]
+    fn promotes_children_of_dropped_items() {
+        // Only pages 2 and 6 are kept, as output pages 1 and 2.
+        let entries = remap_outline(&source(), &|p| match p {
+            2 => Some(1),
+            6 => Some(2),
+            _ => None,
+        });
+        assert_eq!(
+            flat(&entries),
+            vec![(0, "Scope".to_string(), 1), (0, "Table".to_string(), 2)]
+        );
+    }
+
+    #[test]
+    fn moves_nested_entries() {
+        let mut entries = remap_outline(&source(), &Some);
+        move_outline_pages(&mut entries, &|p| p.div_ceil(2));
+        assert_eq!(
+            flat(&entries),
+            vec![
+                (0, "Intro".to_string(), 1),
+                (1, "Scope".to_string(), 1),
+                (0, "Appendix".to_string(), 3),
+                (1, "Table".to_string(), 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn pdfmarks_count_direct_children() {
+        let entries = remap_outline(&source(), &Some);
+        assert_eq!(
+            outline_pdfmarks(&entries),
+            "[ /Count 1 /Title (Intro) /Page 1 /View [/XYZ null null null] /OUT pdfmark\n\
+             [ /Title (Scope) /Page 2 /View [/XYZ null null null] /OUT pdfmark\n\
+             [ /Count 1 /Title (Appendix) /Page 5 /View [/XYZ null null null] /OUT pdfmark\n\
+             [ /Title (Table) /Page 6 /View [/XYZ null null null] /OUT pdfmark\n"
+        );
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
 use std::process::Output;
 use std::process::Stdio;
@@ -11,11 +12,127 @@ use tokio::time::timeout;
 
 use crate::constants::MAX_FILE_BYTES;
 use crate::error::AppError;
+use crate::forms::{
+    fill_update, flatten_hide_update, flatten_restore_update, form_fields, FlattenMode, FormField,
+};
+use crate::geometry::{
+    crop_update_json, page_geometry_from_qpdf_json, CropSpec, PageFit, PageGeometry,
+    PageSizeOptions,
+};
+use crate::impose::ImpositionOptions;
+use crate::inspect::{inspect_report, InspectReport};
+use crate::metadata::strip_update;
+use crate::outline::SourceOutlineItem;
+use crate::ranges::PageRange;
+use crate::stamp::separator_postscript;
+use crate::util::paper_size_points;
 
 #[derive(serde::Deserialize)]
 pub(crate) struct MergePageRef {
     pub(crate) doc: String,
     pub(crate) page: usize,
+    #[serde(default)]
+    pub(crate) rotate: Option<RotateSpec>,
+    #[serde(default)]
+    pub(crate) crop: Option<CropSpec>,
+}
+
+// One output page of the merge layout: a page of an uploaded document, a blank
+// page, or a separator sheet titled after the next document.
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+pub(crate) enum LayoutEntry {
+    Page(MergePageRef),
+    Blank(BlankPageSpec),
+    Separator(SeparatorSpec),
+}
+
+impl LayoutEntry {
+    pub(crate) fn page_ref(&self) -> Option<&MergePageRef> {
+        match self {
+            LayoutEntry::Page(r) => Some(r),
+            LayoutEntry::Blank(_) | LayoutEntry::Separator(_) => None,
+        }
+    }
+}
+
+// Without `size` these take the size of the nearest document page in the layout.
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct BlankPageSpec {
+    pub(crate) blank: bool,
+    #[serde(default)]
+    pub(crate) size: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SeparatorSpec {
+    pub(crate) separator: bool,
+    #[serde(default)]
+    pub(crate) size: Option<String>,
+    #[serde(default)]
+    pub(crate) title: Option<String>,
+}
+
+pub(crate) fn parse_layout_page_size(size: Option<&str>) -> Result<Option<(f64, f64)>, AppError> {
+    let Some(size) = size else {
+        return Ok(None);
+    };
+    paper_size_points(size).map(Some).ok_or_else(|| {
+        AppError::BadRequest(format!(
+            "Invalid layout page size: {size} (expected a3, a4, a5, letter, legal, tabloid or WxH in points)"
+        ))
+    })
+}
+
+// A bare angle (JSON number or "90") sets the page rotation; a signed string
+// ("+90", "-90") turns the page relative to its current rotation.
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+pub(crate) enum RotateSpec {
+    Degrees(i64),
+    Text(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct PageRotation {
+    pub(crate) degrees: i64,
+    pub(crate) relative: bool,
+}
+
+impl RotateSpec {
+    pub(crate) fn to_rotation(&self) -> Option<PageRotation> {
+        let (degrees, relative) = match self {
+            RotateSpec::Degrees(d) => (*d, false),
+            RotateSpec::Text(t) => {
+                let t = t.trim();
+                let relative = t.starts_with('+') || t.starts_with('-');
+                (t.parse::<i64>().ok()?, relative)
+            }
+        };
+        if !matches!(degrees.abs(), 0 | 90 | 180 | 270) || (!relative && degrees < 0) {
+            return None;
+        }
+        Some(PageRotation { degrees, relative })
+    }
+
+    pub(crate) fn display(&self) -> String {
+        match self {
+            RotateSpec::Degrees(d) => d.to_string(),
+            RotateSpec::Text(t) => t.clone(),
+        }
+    }
+}
+
+impl PageRotation {
+    fn qpdf_angle(&self) -> String {
+        if self.relative {
+            format!("{:+}", self.degrees)
+        } else {
+            self.degrees.to_string()
+        }
+    }
 }
 
 pub(crate) async fn write_multipart_field_to_file(
@@ -58,6 +175,184 @@ pub(crate) async fn looks_like_pdf(path: &Path) -> Result<bool, AppError> {
     Ok(n == 5 && &buf == b"%PDF-")
 }
 
+pub(crate) async fn looks_like_image(path: &Path) -> Result<bool, AppError> {
+    let mut f = tokio::fs::File::open(path)
+        .await
+        .map_err(|e| AppError::Internal(e.to_string()))?;
+    let mut buf = [0u8; 8];
+    let n = f
+        .read(&mut buf)
+        .await
+        .map_err(|e| AppError::Internal(e.to_string()))?;
+    let head = &buf[..n];
+    Ok(head.starts_with(&[0xFF, 0xD8, 0xFF])
+        || head.starts_with(b"\x89PNG\r\n\x1a\n")
+        || head.starts_with(b"II*\0")
+        || head.starts_with(b"MM\0*"))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ImageFit {
+    Into,
+    Fill,
+    Exact,
+    Shrink,
+    Enlarge,
+}
+
+impl ImageFit {
+    pub(crate) fn parse(s: &str) -> Result<Self, AppError> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "into" => Ok(Self::Into),
+            "fill" => Ok(Self::Fill),
+            "exact" => Ok(Self::Exact),
+            "shrink" => Ok(Self::Shrink),
+            "enlarge" => Ok(Self::Enlarge),
+            other => Err(AppError::BadRequest(format!(
+                "Invalid image fit: {other} (expected into, fill, exact, shrink or enlarge)"
+            ))),
+        }
+    }
+
+    fn as_img2pdf(self) -> &'static str {
+        match self {
+            Self::Into => "into",
+            Self::Fill => "fill",
+            Self::Exact => "exact",
+            Self::Shrink => "shrink",
+            Self::Enlarge => "enlarge",
+        }
+    }
+}
+
+pub(crate) struct ImageToPdfOptions {
+    // Page size in points; `None` makes the page as large as the image.
+    pub(crate) page_size: Option<(f64, f64)>,
+    pub(crate) fit: ImageFit,
+    pub(crate) margin_pt: f64,
+}
+
+impl Default for ImageToPdfOptions {
+    fn default() -> Self {
+        Self {
+            page_size: None,
+            fit: ImageFit::Into,
+            margin_pt: 0.0,
+        }
+    }
+}
+
+impl ImageToPdfOptions {
+    // Builds the options from the `image_*` multipart fields.
+    pub(crate) fn from_fields(fields: &HashMap<String, String>) -> Result<Self, AppError> {
+        let mut opts = Self::default();
+        for (name, value) in fields {
+            let value = value.trim();
+            match name.as_str() {
+                "image_page_size" => opts.page_size = parse_image_page_size(value)?,
+                "image_fit" => opts.fit = ImageFit::parse(value)?,
+                "image_margin" => {
+                    let margin = value
+                        .parse::<f64>()
+                        .map_err(|_| AppError::BadRequest("Invalid image_margin".to_string()))?;
+                    if !(0.0..=144.0).contains(&margin) {
+                        return Err(AppError::BadRequest(
+                            "image_margin must be between 0 and 144 points".to_string(),
+                        ));
+                    }
+                    opts.margin_pt = margin;
+                }
+                other => {
+                    return Err(AppError::BadRequest(format!(
+                        "Unexpected form field: {other}"
+                    )))
+                }
+            }
+        }
+        // img2pdf only fits images into a given page size.
+        if fields.contains_key("image_fit") && opts.page_size.is_none() {
+            return Err(AppError::BadRequest(
+                "image_fit requires image_page_size".to_string(),
+            ));
+        }
+        Ok(opts)
+    }
+}
+
+// Named sizes or `WxH` in points; `auto` keeps the image size.
+fn parse_image_page_size(s: &str) -> Result<Option<(f64, f64)>, AppError> {
+    if s.is_empty() || s.eq_ignore_ascii_case("auto") {
+        return Ok(None);
+    }
+    paper_size_points(s).map(Some).ok_or_else(|| {
+        AppError::BadRequest(format!(
+            "Invalid image page size: {s} (expected a3, a4, a5, letter, legal, tabloid, auto or WxH in points)"
+        ))
+    })
+}
+
+// Converts a rendered PNG to WebP next to it.
+pub(crate) async fn cwebp_convert_with_timeout(
+    input_path: &Path,
+    process_timeout: Duration,
+) -> Result<PathBuf, AppError> {
+    let output_path = input_path.with_extension("webp");
+
+    let mut cmd = Command::new("cwebp");
+    cmd.arg("-quiet")
+        .arg("-q")
+        .arg("80")
+        .arg(input_path)
+        .arg("-o")
+        .arg(&output_path);
+
+    let output = output_with_timeout(cmd, process_timeout, "cwebp").await?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        return Err(AppError::Internal(format!("cwebp failed: {stderr}")));
+    }
+
+    Ok(output_path)
+}
+
+pub(crate) async fn img2pdf_convert_with_timeout(
+    tmp: &TempDir,
+    input_path: &Path,
+    file_name: &str,
+    options: &ImageToPdfOptions,
+    process_timeout: Duration,
+) -> Result<PathBuf, AppError> {
+    let out_path = tmp.path().join(format!("img_{}.pdf", uuid::Uuid::new_v4()));
+
+    let mut cmd = Command::new("img2pdf");
+    if let Some((w, h)) = options.page_size {
+        cmd.arg(format!("--pagesize={w}ptx{h}pt"))
+            .arg(format!("--fit={}", options.fit.as_img2pdf()));
+    }
+    if options.margin_pt > 0.0 {
+        cmd.arg(format!("--border={m}pt:{m}pt", m = options.margin_pt));
+    }
+    cmd.arg("-o").arg(&out_path).arg(input_path);
+
+    let output = output_with_timeout(cmd, process_timeout, "img2pdf").await?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        let reason = stderr
+            .lines()
+            .rev()
+            .find(|l| !l.trim().is_empty())
+            .unwrap_or("unknown error");
+        return Err(AppError::Unprocessable(format!(
+            "Could not convert {file_name} to PDF: {}",
+            truncate_for_log(reason.trim())
+        )));
+    }
+
+    Ok(out_path)
+}
+
 pub(crate) async fn qpdf_show_npages_with_timeout(
     path: &Path,
     process_timeout: Duration,
@@ -87,128 +382,1835 @@ pub(crate) async fn qpdf_show_npages_with_timeout(
     Ok(pages)
 }
 
-pub(crate) async fn qpdf_assemble_pages_with_timeout(
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum InputEncryption {
+    None,
+    Unlocked,
+    PasswordRequired,
+}
+
+pub(crate) async fn qpdf_check_encryption_with_timeout(
     tmp: &TempDir,
-    inputs_by_id: &std::collections::HashMap<String, PathBuf>,
-    layout: &[MergePageRef],
+    path: &Path,
+    password: Option<&str>,
     process_timeout: Duration,
-) -> Result<PathBuf, AppError> {
-    let output_path = tmp
-        .path()
-        .join(format!("assembled_{}.pdf", uuid::Uuid::new_v4()));
-
+) -> Result<InputEncryption, AppError> {
     let mut cmd = Command::new("qpdf");
-    cmd.arg("--empty").arg("--pages");
-    for r in layout {
-        let path = inputs_by_id
-            .get(&r.doc)
-            .ok_or_else(|| AppError::BadRequest(format!("Unknown doc id: {}", r.doc)))?;
-        cmd.arg(path).arg(r.page.to_string());
-    }
-    cmd.arg("--").arg(&output_path);
-
-    let output = output_with_timeout(cmd, process_timeout, "qpdf").await?;
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
-        return Err(AppError::Internal(format!("qpdf failed: {stderr}")));
-    }
+    cmd.arg("--requires-password");
+    let password_file = write_password_file(tmp, password, &mut cmd).await?;
+    cmd.arg(path);
 
-    Ok(output_path)
-}
+    let output = output_with_timeout(cmd, process_timeout, "qpdf").await;
+    remove_password_file(password_file).await;
+    let output = output?;
 
-fn quality_to_gs_params(quality: u8) -> (i32, i32) {
-    let q = quality.clamp(10, 100) as f64;
-    let t = (q - 10.0) / 90.0;
-    let dpi = (72.0 + t * (300.0 - 72.0)).round() as i32;
-    let jpegq = (20.0 + t * (95.0 - 20.0)).round() as i32;
-    (dpi, jpegq)
+    // qpdf --requires-password: 0 = password needed, 2 = not encrypted,
+    // 3 = encrypted but the supplied (or empty) password opens it.
+    match output.status.code() {
+        Some(0) => Ok(InputEncryption::PasswordRequired),
+        Some(2) => Ok(InputEncryption::None),
+        Some(3) => Ok(InputEncryption::Unlocked),
+        _ => {
+            let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+            Err(AppError::Internal(format!(
+                "qpdf failed: {}",
+                redact(&stderr, &[password.unwrap_or_default()])
+            )))
+        }
+    }
 }
 
-pub(crate) async fn qpdf_linearize_file_with_timeout(
+pub(crate) async fn qpdf_decrypt_file_with_timeout(
     tmp: &TempDir,
     input_path: &Path,
+    password: Option<&str>,
     process_timeout: Duration,
 ) -> Result<PathBuf, AppError> {
     let out_path = tmp
         .path()
-        .join(format!("lin_out_{}.pdf", uuid::Uuid::new_v4()));
+        .join(format!("dec_out_{}.pdf", uuid::Uuid::new_v4()));
 
     let mut cmd = Command::new("qpdf");
-    cmd.arg("--linearize").arg(input_path).arg(&out_path);
+    cmd.arg("--decrypt");
+    let password_file = write_password_file(tmp, password, &mut cmd).await?;
+    cmd.arg(input_path).arg(&out_path);
 
-    let output = output_with_timeout(cmd, process_timeout, "qpdf").await?;
+    let output = output_with_timeout(cmd, process_timeout, "qpdf").await;
+    remove_password_file(password_file).await;
+    let output = output?;
 
     if !output.status.success() {
         let stderr = String::from_utf8_lossy(&output.stderr).to_string();
-        return Err(AppError::Internal(format!("qpdf failed: {stderr}")));
+        return Err(AppError::Internal(format!(
+            "qpdf decryption failed: {}",
+            redact(&stderr, &[password.unwrap_or_default()])
+        )));
     }
 
     Ok(out_path)
 }
 
-pub(crate) async fn merge_with_ghostscript_to_file_with_timeout(
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum RepairStrategy {
+    // qpdf could process the file, so it was left alone.
+    None,
+    Qpdf,
+    Ghostscript,
+}
+
+impl RepairStrategy {
+    pub(crate) fn label(self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Qpdf => "qpdf",
+            Self::Ghostscript => "ghostscript",
+        }
+    }
+}
+
+pub(crate) struct RepairReport {
+    pub(crate) strategy: RepairStrategy,
+    pub(crate) warning_count: usize,
+    // The first warnings, at most MAX_REPAIR_WARNING_BYTES of them so they fit
+    // in a response header.
+    pub(crate) warnings: Vec<String>,
+}
+
+const MAX_REPAIR_WARNING_BYTES: usize = 2048;
+
+// Checks the file with qpdf and, if qpdf reports anything, rewrites it: first
+// with qpdf (which reconstructs broken xref tables while reading), then by
+// re-rendering it with Ghostscript. Either result must be readable by
+// `qpdf --check`.
+pub(crate) async fn repair_pdf_with_timeout(
     tmp: &TempDir,
-    input_paths: &[PathBuf],
-    quality: u8,
+    input_path: &Path,
+    file_name: &str,
     process_timeout: Duration,
-) -> Result<PathBuf, AppError> {
-    let output_path = tmp.path().join(format!("out_{}.pdf", uuid::Uuid::new_v4()));
-    let (dpi, jpegq) = quality_to_gs_params(quality);
+) -> Result<(PathBuf, RepairReport), AppError> {
+    let (check, all_warnings) =
+        qpdf_check_with_timeout(input_path, file_name, process_timeout).await?;
+    let warning_count = all_warnings.len();
+    let mut budget = MAX_REPAIR_WARNING_BYTES;
+    let warnings: Vec<String> = all_warnings
+        .into_iter()
+        .take_while(|w| match budget.checked_sub(w.len()) {
+            Some(rest) => {
+                budget = rest;
+                true
+            }
+            None => false,
+        })
+        .collect();
+    let report = |strategy| RepairReport {
+        strategy,
+        warning_count,
+        warnings: warnings.clone(),
+    };
+    // Exit 3 with warnings is how qpdf reports a table it had to rebuild.
+    if check == QpdfCheck::Clean && warning_count == 0 {
+        return Ok((input_path.to_path_buf(), report(RepairStrategy::None)));
+    }
 
+    let rewritten = tmp
+        .path()
+        .join(format!("repair_qpdf_{}.pdf", uuid::Uuid::new_v4()));
+    let mut cmd = Command::new("qpdf");
+    cmd.arg(input_path).arg(&rewritten);
+    let output = output_with_timeout(cmd, process_timeout, "qpdf").await?;
+    if matches!(output.status.code(), Some(0 | 3))
+        && qpdf_check_with_timeout(&rewritten, file_name, process_timeout)
+            .await?
+            .0
+            != QpdfCheck::Failed
+    {
+        return Ok((rewritten, report(RepairStrategy::Qpdf)));
+    }
+
+    let rendered = tmp
+        .path()
+        .join(format!("repair_gs_{}.pdf", uuid::Uuid::new_v4()));
     let mut cmd = Command::new("gs");
     cmd.arg("-q")
         .arg("-dNOPAUSE")
         .arg("-dBATCH")
         .arg("-sDEVICE=pdfwrite")
-        .arg("-dCompatibilityLevel=1.4")
-        .arg("-dDetectDuplicateImages=true")
-        .arg("-dCompressFonts=true")
-        .arg("-dSubsetFonts=true")
-        .arg("-dDownsampleColorImages=true")
-        .arg("-dDownsampleGrayImages=true")
-        .arg("-dDownsampleMonoImages=true")
-        .arg("-dColorImageDownsampleType=/Bicubic")
-        .arg("-dGrayImageDownsampleType=/Bicubic")
-        .arg(format!("-dColorImageResolution={dpi}"))
-        .arg(format!("-dGrayImageResolution={dpi}"))
-        .arg("-dMonoImageResolution=600")
-        .arg(format!("-dJPEGQ={jpegq}"))
-        .arg(format!("-sOutputFile={}", output_path.to_string_lossy()));
+        .arg("-dAutoRotatePages=/None")
+        .arg("-dPassThroughJPEGImages=true")
+        .arg("-dDownsampleColorImages=false")
+        .arg("-dDownsampleGrayImages=false")
+        .arg("-dDownsampleMonoImages=false")
+        .arg(format!("-sOutputFile={}", rendered.to_string_lossy()))
+        .arg(input_path);
+    let output = output_with_timeout(cmd, process_timeout, "ghostscript").await?;
+    if output.status.success()
+        && qpdf_check_with_timeout(&rendered, file_name, process_timeout)
+            .await?
+            .0
+            != QpdfCheck::Failed
+    {
+        return Ok((rendered, report(RepairStrategy::Ghostscript)));
+    }
 
-    for p in input_paths {
-        cmd.arg(p);
+    Err(AppError::Unprocessable(format!(
+        "{file_name} is damaged and could not be repaired"
+    )))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum QpdfCheck {
+    Clean,
+    // Readable, but qpdf had to work around problems (exit 3).
+    Warnings,
+    Failed,
+}
+
+// `qpdf --check`, plus what qpdf complained about (with the temp path replaced
+// by `file_name`).
+async fn qpdf_check_with_timeout(
+    path: &Path,
+    file_name: &str,
+    process_timeout: Duration,
+) -> Result<(QpdfCheck, Vec<String>), AppError> {
+    let mut cmd = Command::new("qpdf");
+    cmd.arg("--check").arg(path);
+
+    let output = output_with_timeout(cmd, process_timeout, "qpdf").await?;
+
+    let path = path.to_string_lossy();
+    let warnings = String::from_utf8_lossy(&output.stderr)
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(|l| truncate_for_log(&l.replace(path.as_ref(), file_name)))
+        .collect();
+    let check = match output.status.code() {
+        Some(0) => QpdfCheck::Clean,
+        Some(3) => QpdfCheck::Warnings,
+        _ => QpdfCheck::Failed,
+    };
+    Ok((check, warnings))
+}
+
+async fn write_password_file(
+    tmp: &TempDir,
+    password: Option<&str>,
+    cmd: &mut Command,
+) -> Result<Option<PathBuf>, AppError> {
+    let Some(password) = password else {
+        return Ok(None);
+    };
+
+    let path = tmp.path().join(format!("pw_{}.txt", uuid::Uuid::new_v4()));
+    tokio::fs::write(&path, password)
+        .await
+        .map_err(|e| AppError::Internal(e.to_string()))?;
+    cmd.arg(format!("--password-file={}", path.to_string_lossy()));
+    Ok(Some(path))
+}
+
+async fn remove_password_file(path: Option<PathBuf>) {
+    if let Some(path) = path {
+        let _ = tokio::fs::remove_file(path).await;
     }
+}
 
-    let output = output_with_timeout(cmd, process_timeout, "ghostscript").await?;
+pub(crate) async fn qpdf_outline_with_timeout(
+    path: &Path,
+    process_timeout: Duration,
+) -> Result<Vec<SourceOutlineItem>, AppError> {
+    #[derive(serde::Deserialize)]
+    struct OutlinesJson {
+        #[serde(default)]
+        outlines: Vec<SourceOutlineItem>,
+    }
+
+    let mut cmd = Command::new("qpdf");
+    cmd.arg("--json=2").arg("--json-key=outlines").arg(path);
+
+    let output = output_with_timeout(cmd, process_timeout, "qpdf").await?;
 
     if !output.status.success() {
         let stderr = String::from_utf8_lossy(&output.stderr).to_string();
-        return Err(AppError::Internal(format!("ghostscript failed: {stderr}")));
+        return Err(AppError::Internal(format!("qpdf failed: {stderr}")));
     }
 
-    Ok(output_path)
+    let parsed: OutlinesJson = serde_json::from_slice(&output.stdout).map_err(|e| {
+        AppError::Internal(format!(
+            "Failed to parse qpdf outlines JSON: {e} (stdout={})",
+            truncate_for_log(&String::from_utf8_lossy(&output.stdout))
+        ))
+    })?;
+    Ok(parsed.outlines)
 }
 
-async fn output_with_timeout(
-    mut cmd: Command,
+pub(crate) async fn qpdf_page_geometry_with_timeout(
+    path: &Path,
     process_timeout: Duration,
-    what: &str,
-) -> Result<Output, AppError> {
-    cmd.stdin(Stdio::null());
-    cmd.stdout(Stdio::piped());
-    cmd.stderr(Stdio::piped());
-    cmd.kill_on_drop(true);
-    let child = cmd
-        .spawn()
-        .map_err(|e| AppError::Internal(format!("Failed to start {what}: {e}")))?;
+) -> Result<Vec<PageGeometry>, AppError> {
+    let json = qpdf_json_with_timeout(path, &["pages"], process_timeout).await?;
+    page_geometry_from_qpdf_json(&json)
+}
 
-    match timeout(process_timeout, child.wait_with_output()).await {
-        Ok(output) => output.map_err(|e| AppError::Internal(format!("{what} failed: {e}"))),
-        Err(_) => Err(AppError::Internal(format!(
-            "{what} timed out after {}s",
-            process_timeout.as_secs()
-        ))),
+// `qpdf --json=2` output limited to the given top-level keys plus the object
+// table ("qpdf").
+async fn qpdf_json_with_timeout(
+    path: &Path,
+    keys: &[&str],
+    process_timeout: Duration,
+) -> Result<serde_json::Value, AppError> {
+    let mut cmd = Command::new("qpdf");
+    cmd.arg("--json=2");
+    for key in keys {
+        cmd.arg(format!("--json-key={key}"));
+    }
+    cmd.arg("--json-key=qpdf").arg(path);
+
+    let output = output_with_timeout(cmd, process_timeout, "qpdf").await?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        return Err(AppError::Internal(format!("qpdf failed: {stderr}")));
+    }
+
+    let parsed: serde_json::Value = serde_json::from_slice(&output.stdout).map_err(|e| {
+        AppError::Internal(format!(
+            "Failed to parse qpdf JSON: {e} (stdout={})",
+            truncate_for_log(&String::from_utf8_lossy(&output.stdout))
+        ))
+    })?;
+    Ok(parsed)
+}
+
+// Structure report of the file as uploaded, so encryption details are still
+// there; `password` opens it if needed.
+pub(crate) async fn qpdf_inspect_with_timeout(
+    tmp: &TempDir,
+    path: &Path,
+    password: Option<&str>,
+    process_timeout: Duration,
+) -> Result<InspectReport, AppError> {
+    let mut cmd = Command::new("qpdf");
+    cmd.arg("--json=2");
+    for key in [
+        "pages",
+        "encrypt",
+        "acroform",
+        "attachments",
+        "outlines",
+        "qpdf",
+    ] {
+        cmd.arg(format!("--json-key={key}"));
+    }
+    let password_file = write_password_file(tmp, password, &mut cmd).await?;
+    cmd.arg(path);
+
+    let output = output_with_timeout(cmd, process_timeout, "qpdf").await;
+    remove_password_file(password_file).await;
+    let output = output?;
+
+    // Exit code 3 only means qpdf had warnings about the file.
+    if !output.status.success() && output.status.code() != Some(3) {
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        return Err(AppError::Internal(format!(
+            "qpdf failed: {}",
+            redact(&stderr, &[password.unwrap_or_default()])
+        )));
+    }
+
+    let parsed: serde_json::Value = serde_json::from_slice(&output.stdout).map_err(|e| {
+        AppError::Internal(format!(
+            "Failed to parse qpdf JSON: {e} (stdout={})",
+            truncate_for_log(&String::from_utf8_lossy(&output.stdout))
+        ))
+    })?;
+    inspect_report(&parsed)
+}
+
+// Sets CropBox and TrimBox per page (`crops[i]` applies to page i + 1) by
+// rewriting the page objects; content is left untouched.
+pub(crate) async fn crop_pages_with_timeout(
+    tmp: &TempDir,
+    input_path: &Path,
+    crops: &[Option<CropSpec>],
+    process_timeout: Duration,
+) -> Result<PathBuf, AppError> {
+    let json = qpdf_json_with_timeout(input_path, &["pages"], process_timeout).await?;
+    let pages = page_geometry_from_qpdf_json(&json)?;
+    if crops.len() != pages.len() {
+        return Err(AppError::Internal(format!(
+            "Crop list covers {} pages, document has {}",
+            crops.len(),
+            pages.len()
+        )));
+    }
+
+    let auto_pages: Vec<usize> = crops
+        .iter()
+        .enumerate()
+        .filter(|(_, c)| c.as_ref().is_some_and(|c| c.auto))
+        .map(|(idx, _)| idx + 1)
+        .collect();
+    let content: HashMap<usize, [f64; 4]> = if auto_pages.is_empty() {
+        HashMap::new()
+    } else {
+        let boxes = ghostscript_bbox_with_timeout(input_path, &auto_pages, process_timeout).await?;
+        auto_pages
+            .iter()
+            .zip(boxes)
+            .filter_map(|(page, b)| b.map(|b| (*page, b)))
+            .collect()
+    };
+
+    let mut boxes: Vec<(usize, [f64; 4])> = Vec::new();
+    for (idx, (crop, page)) in crops.iter().zip(&pages).enumerate() {
+        let Some(crop) = crop else {
+            continue;
+        };
+        let cropped = crop
+            .crop_box(page, content.get(&(idx + 1)).copied())
+            .map_err(|e| match e {
+                AppError::BadRequest(msg) => {
+                    AppError::BadRequest(format!("{msg} (output page {})", idx + 1))
+                }
+                other => other,
+            })?;
+        if let Some(cropped) = cropped {
+            boxes.push((idx, cropped));
+        }
+    }
+    if boxes.is_empty() {
+        return Ok(input_path.to_path_buf());
+    }
+
+    let update = crop_update_json(&json, &boxes)?;
+    qpdf_update_with_timeout(tmp, input_path, Some(&update), &[], false, process_timeout).await
+}
+
+// Rewrites the file with qpdf, first applying `update` (a `--update-from-json`
+// document) and then `args`. qpdf exits with 3 when it only had warnings; with
+// `allow_warnings` that counts as success.
+async fn qpdf_update_with_timeout(
+    tmp: &TempDir,
+    input_path: &Path,
+    update: Option<&serde_json::Value>,
+    args: &[&str],
+    allow_warnings: bool,
+    process_timeout: Duration,
+) -> Result<PathBuf, AppError> {
+    let output_path = tmp
+        .path()
+        .join(format!("updated_{}.pdf", uuid::Uuid::new_v4()));
+
+    let mut cmd = Command::new("qpdf");
+    cmd.arg(input_path);
+    if let Some(update) = update {
+        let update_path = tmp
+            .path()
+            .join(format!("update_{}.json", uuid::Uuid::new_v4()));
+        let update = serde_json::to_vec(update).map_err(|e| AppError::Internal(e.to_string()))?;
+        tokio::fs::write(&update_path, update)
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+        cmd.arg(format!(
+            "--update-from-json={}",
+            update_path.to_string_lossy()
+        ));
+    }
+    cmd.args(args).arg(&output_path);
+
+    let output = output_with_timeout(cmd, process_timeout, "qpdf").await?;
+
+    let warnings_only = allow_warnings && output.status.code() == Some(3);
+    if !output.status.success() && !warnings_only {
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        return Err(AppError::Internal(format!("qpdf failed: {stderr}")));
+    }
+
+    Ok(output_path)
+}
+
+// Burns annotation and/or form field appearances into the page content. qpdf
+// can't pick annotation types, so the ones to keep are hidden from a "screen"
+// flatten via their NoView flag and restored afterwards.
+pub(crate) async fn qpdf_flatten_with_timeout(
+    tmp: &TempDir,
+    input_path: &Path,
+    mode: FlattenMode,
+    process_timeout: Duration,
+) -> Result<PathBuf, AppError> {
+    let json = qpdf_json_with_timeout(input_path, &["pages"], process_timeout).await?;
+    let hide = flatten_hide_update(&json, mode)?;
+
+    let mut args = Vec::new();
+    if mode != FlattenMode::Annotations {
+        args.push("--generate-appearances");
+    }
+    args.push("--flatten-annotations=screen");
+    // Forms and annotations commonly draw warnings (e.g. missing appearances)
+    // that don't affect the result.
+    let flattened =
+        qpdf_update_with_timeout(tmp, input_path, hide.as_ref(), &args, true, process_timeout)
+            .await?;
+    if hide.is_none() {
+        return Ok(flattened);
+    }
+
+    let json = qpdf_json_with_timeout(&flattened, &["pages"], process_timeout).await?;
+    match flatten_restore_update(&json)? {
+        Some(restore) => {
+            qpdf_update_with_timeout(tmp, &flattened, Some(&restore), &[], true, process_timeout)
+                .await
+        }
+        None => Ok(flattened),
+    }
+}
+
+pub(crate) async fn qpdf_form_fields_with_timeout(
+    path: &Path,
+    process_timeout: Duration,
+) -> Result<Vec<FormField>, AppError> {
+    let json = qpdf_json_with_timeout(path, &["acroform"], process_timeout).await?;
+    form_fields(&json)
+}
+
+// Sets form field values (field name -> value) and regenerates the appearances
+// of text and choice fields; `flatten` then burns the fields into the pages.
+pub(crate) async fn fill_form_with_timeout(
+    tmp: &TempDir,
+    input_path: &Path,
+    values: &serde_json::Map<String, serde_json::Value>,
+    flatten: bool,
+    process_timeout: Duration,
+) -> Result<PathBuf, AppError> {
+    let json = qpdf_json_with_timeout(input_path, &["acroform"], process_timeout).await?;
+    let update = fill_update(&json, values)?;
+    let filled = qpdf_update_with_timeout(
+        tmp,
+        input_path,
+        Some(&update),
+        &["--generate-appearances"],
+        true,
+        process_timeout,
+    )
+    .await?;
+    if !flatten {
+        return Ok(filled);
+    }
+    qpdf_flatten_with_timeout(tmp, &filled, FlattenMode::Forms, process_timeout).await
+}
+
+// Content bounding box of each listed page in displayed (rotated, CropBox-relative)
+// space, `None` for blank pages.
+async fn ghostscript_bbox_with_timeout(
+    input_path: &Path,
+    pages: &[usize],
+    process_timeout: Duration,
+) -> Result<Vec<Option<[f64; 4]>>, AppError> {
+    let page_list = pages
+        .iter()
+        .map(usize::to_string)
+        .collect::<Vec<_>>()
+        .join(",");
+
+    let mut cmd = Command::new("gs");
+    cmd.arg("-q")
+        .arg("-dNOPAUSE")
+        .arg("-dBATCH")
+        .arg("-sDEVICE=bbox")
+        .arg("-dUseCropBox")
+        .arg(format!("-sPageList={page_list}"))
+        .arg(input_path);
+
+    let output = output_with_timeout(cmd, process_timeout, "ghostscript").await?;
+
+    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+    if !output.status.success() {
+        return Err(AppError::Internal(format!("ghostscript failed: {stderr}")));
+    }
+
+    // The bbox device reports on stderr, one %%HiResBoundingBox line per page.
+    let boxes: Vec<Option<[f64; 4]>> = stderr
+        .lines()
+        .filter_map(|line| line.strip_prefix("%%HiResBoundingBox:"))
+        .map(|rest| {
+            let n: Vec<f64> = rest
+                .split_whitespace()
+                .filter_map(|v| v.parse().ok())
+                .collect();
+            match n[..] {
+                [x0, y0, x1, y1] if x1 > x0 && y1 > y0 => Some([x0, y0, x1, y1]),
+                _ => None,
+            }
+        })
+        .collect();
+    if boxes.len() != pages.len() {
+        return Err(AppError::Internal(format!(
+            "ghostscript bbox reported {} pages, expected {}: {}",
+            boxes.len(),
+            pages.len(),
+            truncate_for_log(&stderr)
+        )));
+    }
+    Ok(boxes)
+}
+
+// `generated` holds the one-page PDFs for the blank and separator entries,
+// keyed by layout index.
+pub(crate) async fn qpdf_assemble_pages_with_timeout(
+    tmp: &TempDir,
+    inputs_by_id: &HashMap<String, PathBuf>,
+    layout: &[LayoutEntry],
+    generated: &HashMap<usize, PathBuf>,
+    process_timeout: Duration,
+) -> Result<PathBuf, AppError> {
+    let mut selections: Vec<(&Path, String)> = Vec::with_capacity(layout.len());
+    let mut rotations: Vec<(usize, PageRotation)> = Vec::new();
+    for (idx, entry) in layout.iter().enumerate() {
+        let Some(r) = entry.page_ref() else {
+            let path = generated.get(&idx).ok_or_else(|| {
+                AppError::Internal(format!("Missing generated page for layout entry {idx}"))
+            })?;
+            selections.push((path.as_path(), "1".to_string()));
+            continue;
+        };
+        let path = inputs_by_id
+            .get(&r.doc)
+            .ok_or_else(|| AppError::BadRequest(format!("Unknown doc id: {}", r.doc)))?;
+        selections.push((path.as_path(), r.page.to_string()));
+
+        if let Some(spec) = &r.rotate {
+            let rotation = spec.to_rotation().ok_or_else(|| {
+                AppError::BadRequest(format!("Invalid rotation: {}", spec.display()))
+            })?;
+            rotations.push((idx + 1, rotation));
+        }
+    }
+
+    qpdf_select_pages_with_timeout(tmp, "assembled", &selections, &rotations, process_timeout).await
+}
+
+pub(crate) async fn qpdf_extract_range_with_timeout(
+    tmp: &TempDir,
+    input_path: &Path,
+    range: &PageRange,
+    process_timeout: Duration,
+) -> Result<PathBuf, AppError> {
+    qpdf_select_pages_with_timeout(
+        tmp,
+        "part",
+        &[(input_path, range.label())],
+        &[],
+        process_timeout,
+    )
+    .await
+}
+
+// Appends the inputs' pages into one document, in order.
+pub(crate) async fn qpdf_concat_with_timeout(
+    tmp: &TempDir,
+    inputs: &[PathBuf],
+    process_timeout: Duration,
+) -> Result<PathBuf, AppError> {
+    let selections: Vec<(&Path, String)> = inputs
+        .iter()
+        .map(|path| (path.as_path(), "1-z".to_string()))
+        .collect();
+    qpdf_select_pages_with_timeout(tmp, "joined", &selections, &[], process_timeout).await
+}
+
+async fn qpdf_select_pages_with_timeout(
+    tmp: &TempDir,
+    prefix: &str,
+    selections: &[(&Path, String)],
+    rotations: &[(usize, PageRotation)],
+    process_timeout: Duration,
+) -> Result<PathBuf, AppError> {
+    let output_path = tmp
+        .path()
+        .join(format!("{prefix}_{}.pdf", uuid::Uuid::new_v4()));
+
+    let mut cmd = Command::new("qpdf");
+    cmd.arg("--empty");
+    // Page numbers in --rotate refer to the output document produced by --pages.
+    for (page, rotation) in rotations {
+        cmd.arg(format!("--rotate={}:{page}", rotation.qpdf_angle()));
+    }
+    cmd.arg("--pages");
+    for (path, pages) in selections {
+        cmd.arg(path).arg(pages);
+    }
+    cmd.arg("--").arg(&output_path);
+
+    let output = output_with_timeout(cmd, process_timeout, "qpdf").await?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        return Err(AppError::Internal(format!("qpdf failed: {stderr}")));
+    }
+
+    Ok(output_path)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum OutputProfile {
+    Pdf,
+    PdfA1b,
+    PdfA2b,
+    PdfA3b,
+}
+
+impl OutputProfile {
+    pub(crate) fn parse(s: &str) -> Result<Self, AppError> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "pdf" => Ok(Self::Pdf),
+            "pdfa-1b" => Ok(Self::PdfA1b),
+            "pdfa-2b" => Ok(Self::PdfA2b),
+            "pdfa-3b" => Ok(Self::PdfA3b),
+            other => Err(AppError::BadRequest(format!(
+                "Invalid output profile: {other} (expected pdf, pdfa-1b, pdfa-2b or pdfa-3b)"
+            ))),
+        }
+    }
+
+    pub(crate) fn label(self) -> &'static str {
+        match self {
+            Self::Pdf => "pdf",
+            Self::PdfA1b => "pdfa-1b",
+            Self::PdfA2b => "pdfa-2b",
+            Self::PdfA3b => "pdfa-3b",
+        }
+    }
+
+    pub(crate) fn pdfa_part(self) -> Option<u8> {
+        match self {
+            Self::Pdf => None,
+            Self::PdfA1b => Some(1),
+            Self::PdfA2b => Some(2),
+            Self::PdfA3b => Some(3),
+        }
+    }
+
+    fn compatibility_level(self) -> &'static str {
+        match self {
+            Self::Pdf | Self::PdfA1b => "1.4",
+            Self::PdfA2b | Self::PdfA3b => "1.7",
+        }
+    }
+}
+
+pub(crate) struct PdfOutputOptions<'a> {
+    pub(crate) profile: OutputProfile,
+    pub(crate) icc_profile: &'a Path,
+    pub(crate) color: ColorMode,
+    // Gray level above which mono content turns white; `None` when the pages
+    // were already rasterized to 1-bit.
+    pub(crate) mono_threshold: Option<f64>,
+}
+
+// PDF/A needs an output intent, which pdfwrite only gets from a PostScript
+// prologue (Ghostscript's PDFA_def.ps) that embeds the ICC profile. Mono is
+// gray conversion plus a thresholding transfer function; text and vector art
+// snap to black or white, sampled images may keep gray levels.
+async fn add_output_args(
+    cmd: &mut Command,
+    tmp: &TempDir,
+    output: &PdfOutputOptions<'_>,
+    apply_mono_threshold: bool,
+) -> Result<(), AppError> {
+    cmd.arg(format!(
+        "-dCompatibilityLevel={}",
+        output.profile.compatibility_level()
+    ));
+    match output.color {
+        ColorMode::Color => {
+            if output.profile.pdfa_part().is_some() {
+                cmd.arg("-sColorConversionStrategy=RGB");
+            }
+        }
+        ColorMode::Gray | ColorMode::Mono => {
+            cmd.arg("-sColorConversionStrategy=Gray")
+                .arg("-dProcessColorModel=/DeviceGray");
+        }
+    }
+
+    let def_path = match output.profile.pdfa_part() {
+        Some(part) => {
+            let icc_profile = output.icc_profile;
+            if !tokio::fs::try_exists(icc_profile).await.unwrap_or(false) {
+                return Err(AppError::Internal(format!(
+                    "PDF/A ICC profile not found: {}",
+                    icc_profile.display()
+                )));
+            }
+            let def = format!(
+                "%!\n\
+                 /ICCProfile {} def\n\
+                 [/_objdef {{icc_PDFA}} /type /stream /OBJ pdfmark\n\
+                 [{{icc_PDFA}} << /N 3 >> /PUT pdfmark\n\
+                 [{{icc_PDFA}} ICCProfile (r) file /PUT pdfmark\n\
+                 [/_objdef {{OutputIntent_PDFA}} /type /dict /OBJ pdfmark\n\
+                 [{{OutputIntent_PDFA}} <<\n\
+                 /Type /OutputIntent\n\
+                 /S /GTS_PDFA1\n\
+                 /DestOutputProfile {{icc_PDFA}}\n\
+                 /OutputConditionIdentifier (sRGB)\n\
+                 >> /PUT pdfmark\n\
+                 [{{Catalog}} << /OutputIntents [ {{OutputIntent_PDFA}} ] >> /PUT pdfmark\n",
+                crate::stamp::ps_bytes(icc_profile.as_os_str().as_encoded_bytes())
+            );
+            let def_path = tmp
+                .path()
+                .join(format!("pdfa_def_{}.ps", uuid::Uuid::new_v4()));
+            tokio::fs::write(&def_path, def)
+                .await
+                .map_err(|e| AppError::Internal(e.to_string()))?;
+
+            // Policy 1 drops features PDF/A forbids (e.g. transparency in PDF/A-1)
+            // instead of silently writing a non-conforming file.
+            cmd.arg(format!("-dPDFA={part}"))
+                .arg("-dPDFACompatibilityPolicy=1")
+                .arg({
+                    let mut arg = std::ffi::OsString::from("--permit-file-read=");
+                    arg.push(icc_profile);
+                    arg
+                });
+            Some(def_path)
+        }
+        None => None,
+    };
+
+    if let (ColorMode::Mono, Some(threshold), true) =
+        (output.color, output.mono_threshold, apply_mono_threshold)
+    {
+        // The PDF interpreter calls setpagedevice for every page, so Install
+        // re-applies the transfer function page by page.
+        cmd.arg("-dTransferFunctionInfo=/Apply")
+            .arg("-c")
+            .arg(format!(
+                "<< /Install {{ {{ {:.4} gt {{ 1 }} {{ 0 }} ifelse }} settransfer }} >> setpagedevice",
+                threshold.clamp(0.0, 1.0)
+            ))
+            .arg("-f");
+    }
+    if let Some(def_path) = def_path {
+        cmd.arg(def_path);
+    }
+    Ok(())
+}
+
+// Checks what Ghostscript actually wrote: the PDF/A identification in the XMP
+// metadata and the GTS_PDFA1 output intent. This is not a full validator.
+pub(crate) async fn pdfa_identification_present(
+    path: &Path,
+    profile: OutputProfile,
+) -> Result<bool, AppError> {
+    let Some(part) = profile.pdfa_part() else {
+        return Ok(false);
+    };
+    let part_markers = [
+        format!("pdfaid:part='{part}'"),
+        format!("pdfaid:part=\"{part}\""),
+        format!("<pdfaid:part>{part}</pdfaid:part>"),
+    ];
+    let mut needles: Vec<&[u8]> = vec![b"/GTS_PDFA1"];
+    needles.extend(part_markers.iter().map(|m| m.as_bytes()));
+
+    let found = file_contains(path, &needles).await?;
+    Ok(found[0] && found[1..].iter().any(|f| *f))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum PdfaConformance {
+    // No validator is configured (VERAPDF_PATH).
+    Unchecked,
+    Pass,
+    Fail,
+}
+
+impl PdfaConformance {
+    pub(crate) fn label(self) -> &'static str {
+        match self {
+            Self::Unchecked => "unchecked",
+            Self::Pass => "pass",
+            Self::Fail => "fail",
+        }
+    }
+}
+
+// Validates the output against its PDF/A flavour with veraPDF, which reports
+// one "PASS <file> <flavour>" or "FAIL ..." line per file in text format.
+pub(crate) async fn verapdf_validate_with_timeout(
+    verapdf: &Path,
+    path: &Path,
+    profile: OutputProfile,
+    process_timeout: Duration,
+) -> Result<PdfaConformance, AppError> {
+    let Some(part) = profile.pdfa_part() else {
+        return Ok(PdfaConformance::Unchecked);
+    };
+    let mut cmd = Command::new(verapdf);
+    cmd.arg("--flavour")
+        .arg(format!("{part}b"))
+        .arg("--format")
+        .arg("text")
+        .arg(path);
+
+    let output = output_with_timeout(cmd, process_timeout, "veraPDF").await?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    for line in stdout.lines() {
+        let line = line.trim_start();
+        if line.starts_with("PASS ") {
+            return Ok(PdfaConformance::Pass);
+        }
+        if line.starts_with("FAIL ") {
+            return Ok(PdfaConformance::Fail);
+        }
+    }
+
+    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+    Err(AppError::Internal(format!(
+        "veraPDF validation failed: {}",
+        truncate_for_log(&stderr)
+    )))
+}
+
+async fn file_contains(path: &Path, needles: &[&[u8]]) -> Result<Vec<bool>, AppError> {
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .map_err(|e| AppError::Internal(e.to_string()))?;
+    let keep = needles
+        .iter()
+        .map(|n| n.len())
+        .max()
+        .unwrap_or(1)
+        .saturating_sub(1);
+    let mut found = vec![false; needles.len()];
+    let mut window: Vec<u8> = Vec::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file
+            .read(&mut buf)
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+        if n == 0 {
+            break;
+        }
+        window.extend_from_slice(&buf[..n]);
+        for (hit, needle) in found.iter_mut().zip(needles) {
+            if !*hit && window.windows(needle.len()).any(|w| w == *needle) {
+                *hit = true;
+            }
+        }
+        if found.iter().all(|f| *f) {
+            break;
+        }
+        let cut = window.len().saturating_sub(keep);
+        window.drain(..cut);
+    }
+    Ok(found)
+}
+
+fn quality_to_gs_params(quality: u8) -> (i32, i32) {
+    let q = quality.clamp(10, 100) as f64;
+    let t = (q - 10.0) / 90.0;
+    let dpi = (72.0 + t * (300.0 - 72.0)).round() as i32;
+    let jpegq = (20.0 + t * (95.0 - 20.0)).round() as i32;
+    (dpi, jpegq)
+}
+
+pub(crate) async fn qpdf_linearize_file_with_timeout(
+    tmp: &TempDir,
+    input_path: &Path,
+    process_timeout: Duration,
+) -> Result<PathBuf, AppError> {
+    let out_path = tmp
+        .path()
+        .join(format!("lin_out_{}.pdf", uuid::Uuid::new_v4()));
+
+    let mut cmd = Command::new("qpdf");
+    cmd.arg("--linearize").arg(input_path).arg(&out_path);
+
+    let output = output_with_timeout(cmd, process_timeout, "qpdf").await?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        return Err(AppError::Internal(format!("qpdf failed: {stderr}")));
+    }
+
+    Ok(out_path)
+}
+
+#[derive(Clone, Copy, Default)]
+pub(crate) struct OutputPermissions {
+    pub(crate) no_print: bool,
+    pub(crate) no_copy: bool,
+    pub(crate) no_modify: bool,
+}
+
+impl OutputPermissions {
+    pub(crate) fn parse(spec: &str) -> Result<Self, AppError> {
+        let mut perms = Self::default();
+        for token in spec.split(',') {
+            match token.trim().to_ascii_lowercase().as_str() {
+                "" => {}
+                "no-print" => perms.no_print = true,
+                "no-copy" => perms.no_copy = true,
+                "no-modify" => perms.no_modify = true,
+                other => {
+                    return Err(AppError::BadRequest(format!(
+                        "Unknown permission flag: {other} (expected no-print, no-copy, no-modify)"
+                    )))
+                }
+            }
+        }
+        Ok(perms)
+    }
+}
+
+// Deliberately not Debug: the passwords must never end up in logs.
+pub(crate) struct EncryptionOptions {
+    pub(crate) user_password: String,
+    pub(crate) owner_password: String,
+    pub(crate) permissions: OutputPermissions,
+}
+
+pub(crate) async fn qpdf_encrypt_file_with_timeout(
+    tmp: &TempDir,
+    input_path: &Path,
+    encryption: &EncryptionOptions,
+    linearize: bool,
+    process_timeout: Duration,
+) -> Result<PathBuf, AppError> {
+    let out_path = tmp
+        .path()
+        .join(format!("enc_out_{}.pdf", uuid::Uuid::new_v4()));
+
+    // Passwords go through an @file instead of argv so they don't show up in the
+    // process list.
+    let mut args = vec![
+        "--encrypt".to_string(),
+        format!("--user-password={}", encryption.user_password),
+        format!("--owner-password={}", encryption.owner_password),
+        "--bits=256".to_string(),
+    ];
+    if encryption.permissions.no_print {
+        args.push("--print=none".to_string());
+    }
+    if encryption.permissions.no_copy {
+        args.push("--extract=n".to_string());
+    }
+    if encryption.permissions.no_modify {
+        args.push("--modify=none".to_string());
+    }
+    args.push("--".to_string());
+    if linearize {
+        args.push("--linearize".to_string());
+    }
+
+    let args_path = tmp
+        .path()
+        .join(format!("enc_args_{}.txt", uuid::Uuid::new_v4()));
+    tokio::fs::write(&args_path, args.join("\n"))
+        .await
+        .map_err(|e| AppError::Internal(e.to_string()))?;
+
+    let mut cmd = Command::new("qpdf");
+    cmd.arg(format!("@{}", args_path.to_string_lossy()))
+        .arg(input_path)
+        .arg(&out_path);
+
+    let output = output_with_timeout(cmd, process_timeout, "qpdf").await;
+    let _ = tokio::fs::remove_file(&args_path).await;
+    let output = output?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        let stderr = redact(
+            &stderr,
+            &[&encryption.user_password, &encryption.owner_password],
+        );
+        return Err(AppError::Internal(format!(
+            "qpdf encryption failed: {stderr}"
+        )));
+    }
+
+    Ok(out_path)
+}
+
+pub(crate) async fn merge_with_ghostscript_to_file_with_timeout(
+    tmp: &TempDir,
+    input_paths: &[PathBuf],
+    quality: u8,
+    output: &PdfOutputOptions<'_>,
+    process_timeout: Duration,
+) -> Result<PathBuf, AppError> {
+    let output_path = tmp.path().join(format!("out_{}.pdf", uuid::Uuid::new_v4()));
+    let (dpi, jpegq) = quality_to_gs_params(quality);
+
+    let mut cmd = Command::new("gs");
+    cmd.arg("-q")
+        .arg("-dNOPAUSE")
+        .arg("-dBATCH")
+        .arg("-sDEVICE=pdfwrite")
+        .arg("-dDetectDuplicateImages=true")
+        .arg("-dCompressFonts=true")
+        .arg("-dSubsetFonts=true")
+        .arg("-dDownsampleColorImages=true")
+        .arg("-dDownsampleGrayImages=true")
+        .arg("-dDownsampleMonoImages=true")
+        .arg("-dColorImageDownsampleType=/Bicubic")
+        .arg("-dGrayImageDownsampleType=/Bicubic")
+        .arg(format!("-dColorImageResolution={dpi}"))
+        .arg(format!("-dGrayImageResolution={dpi}"))
+        .arg("-dMonoImageResolution=600")
+        .arg(format!("-dJPEGQ={jpegq}"))
+        .arg(format!("-sOutputFile={}", output_path.to_string_lossy()));
+    add_output_args(&mut cmd, tmp, output, true).await?;
+
+    for p in input_paths {
+        cmd.arg(p);
+    }
+
+    let output = output_with_timeout(cmd, process_timeout, "ghostscript").await?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        return Err(AppError::Internal(format!("ghostscript failed: {stderr}")));
+    }
+
+    Ok(output_path)
+}
+
+// Removes the document Info dictionary and XMP metadata.
+pub(crate) async fn qpdf_strip_metadata_with_timeout(
+    tmp: &TempDir,
+    input_path: &Path,
+    process_timeout: Duration,
+) -> Result<PathBuf, AppError> {
+    let json = qpdf_json_with_timeout(input_path, &[], process_timeout).await?;
+    let update = strip_update(&json)?;
+    qpdf_update_with_timeout(tmp, input_path, Some(&update), &[], false, process_timeout).await
+}
+
+pub(crate) async fn stamp_with_ghostscript_to_file_with_timeout(
+    tmp: &TempDir,
+    input_path: &Path,
+    postscript: &str,
+    output: &PdfOutputOptions<'_>,
+    process_timeout: Duration,
+) -> Result<PathBuf, AppError> {
+    let ps_path = tmp
+        .path()
+        .join(format!("stamp_{}.ps", uuid::Uuid::new_v4()));
+    tokio::fs::write(&ps_path, postscript)
+        .await
+        .map_err(|e| AppError::Internal(e.to_string()))?;
+    let output_path = tmp
+        .path()
+        .join(format!("stamped_{}.pdf", uuid::Uuid::new_v4()));
+
+    // Second pdfwrite pass: keep images as they came out of the merge pass.
+    let mut cmd = Command::new("gs");
+    cmd.arg("-q")
+        .arg("-dNOPAUSE")
+        .arg("-dBATCH")
+        .arg("-sDEVICE=pdfwrite")
+        .arg("-dAutoRotatePages=/None")
+        .arg("-dPassThroughJPEGImages=true")
+        .arg("-dDownsampleColorImages=false")
+        .arg("-dDownsampleGrayImages=false")
+        .arg("-dDownsampleMonoImages=false")
+        .arg(format!("-sOutputFile={}", output_path.to_string_lossy()));
+    // Stamps are drawn after thresholding; keep them gray rather than
+    // snapping a translucent watermark to solid black or white.
+    add_output_args(&mut cmd, tmp, output, false).await?;
+    cmd.arg(&ps_path).arg(input_path);
+
+    let output = output_with_timeout(cmd, process_timeout, "ghostscript").await?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        return Err(AppError::Internal(format!("ghostscript failed: {stderr}")));
+    }
+
+    Ok(output_path)
+}
+
+// Drops pages whose ink coverage (in percent of the page area) is at or below
+// `threshold`, except those in `keep`; returns the new file and the removed
+// 1-based page numbers.
+pub(crate) async fn remove_blank_pages_with_timeout(
+    tmp: &TempDir,
+    input_path: &Path,
+    threshold: f64,
+    keep: &HashSet<usize>,
+    process_timeout: Duration,
+) -> Result<(PathBuf, Vec<usize>), AppError> {
+    let coverage = ghostscript_ink_coverage_with_timeout(input_path, process_timeout).await?;
+    let total_pages = qpdf_show_npages_with_timeout(input_path, process_timeout).await?;
+    if coverage.len() != total_pages {
+        return Err(AppError::Internal(format!(
+            "ghostscript inkcov reported {} pages, expected {total_pages}",
+            coverage.len()
+        )));
+    }
+    let (blank, kept): (Vec<usize>, Vec<usize>) = (1..=coverage.len())
+        .partition(|page| coverage[page - 1] <= threshold && !keep.contains(page));
+    if blank.is_empty() {
+        return Ok((input_path.to_path_buf(), blank));
+    }
+    if kept.is_empty() {
+        return Err(AppError::Unprocessable("All pages are blank".to_string()));
+    }
+
+    let kept = kept
+        .iter()
+        .map(usize::to_string)
+        .collect::<Vec<_>>()
+        .join(",");
+    let path = qpdf_select_pages_with_timeout(
+        tmp,
+        "nonblank",
+        &[(input_path, kept)],
+        &[],
+        process_timeout,
+    )
+    .await?;
+    Ok((path, blank))
+}
+
+// Total CMYK ink coverage per page in percent, from a low-resolution inkcov render.
+async fn ghostscript_ink_coverage_with_timeout(
+    input_path: &Path,
+    process_timeout: Duration,
+) -> Result<Vec<f64>, AppError> {
+    let mut cmd = Command::new("gs");
+    cmd.arg("-q")
+        .arg("-dNOPAUSE")
+        .arg("-dBATCH")
+        .arg("-sDEVICE=inkcov")
+        .arg("-r50")
+        .arg("-dUseCropBox")
+        .arg("-sOutputFile=%stdout")
+        .arg(input_path);
+
+    let output = output_with_timeout(cmd, process_timeout, "ghostscript").await?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        return Err(AppError::Internal(format!("ghostscript failed: {stderr}")));
+    }
+
+    // One line per page: " 0.01234  0.00000  0.00000  0.00321 CMYK OK"
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Ok(stdout
+        .lines()
+        .filter(|line| line.trim_end().ends_with("CMYK OK"))
+        .map(|line| {
+            line.split_whitespace()
+                .take(4)
+                .filter_map(|v| v.parse::<f64>().ok())
+                .sum::<f64>()
+                * 100.0
+        })
+        .collect())
+}
+
+// Rescales every page onto one paper size.
+pub(crate) async fn normalize_page_size_with_timeout(
+    tmp: &TempDir,
+    input_path: &Path,
+    options: &PageSizeOptions,
+    process_timeout: Duration,
+) -> Result<PathBuf, AppError> {
+    // Ghostscript scales and centres pages itself for `fit`; it has no
+    // equivalent for the other modes, so those get a placement prologue.
+    let args = if options.fit == PageFit::Fit {
+        vec!["-dPDFFitPage".to_string()]
+    } else {
+        let pages = qpdf_page_geometry_with_timeout(input_path, process_timeout).await?;
+        let ps_path = tmp
+            .path()
+            .join(format!("placement_{}.ps", uuid::Uuid::new_v4()));
+        tokio::fs::write(&ps_path, options.to_postscript(&pages))
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+        vec![ps_path.to_string_lossy().to_string()]
+    };
+
+    ghostscript_fixed_media_with_timeout(
+        tmp,
+        input_path,
+        options.width,
+        options.height,
+        &args,
+        process_timeout,
+    )
+    .await
+}
+
+// Lays the pages out on sheets; returns the imposed PDF and, per source page,
+// the output sheet side it landed on.
+pub(crate) async fn impose_pages_with_timeout(
+    tmp: &TempDir,
+    input_path: &Path,
+    options: &ImpositionOptions,
+    process_timeout: Duration,
+) -> Result<(PathBuf, HashMap<usize, usize>), AppError> {
+    let total_pages = qpdf_show_npages_with_timeout(input_path, process_timeout).await?;
+    let cells = options.cells(total_pages);
+    let (cell_width, cell_height) = options.cell_size();
+
+    let blank = if cells.contains(&None) {
+        Some(
+            ghostscript_blank_page_with_timeout(tmp, cell_width, cell_height, process_timeout)
+                .await?,
+        )
+    } else {
+        None
+    };
+    let mut selections: Vec<(&Path, String)> = Vec::with_capacity(cells.len());
+    for cell in &cells {
+        match (cell, blank.as_deref()) {
+            (Some(page), _) => selections.push((input_path, page.to_string())),
+            (None, Some(blank)) => selections.push((blank, "1".to_string())),
+            (None, None) => {}
+        }
+    }
+    let mut path =
+        qpdf_select_pages_with_timeout(tmp, "ordered", &selections, &[], process_timeout).await?;
+
+    // The nup device scales every page to fill its cell, so the gutter is
+    // baked in first: shrink onto the inner area, then centre on a full cell.
+    if options.gutter > 0.0 {
+        let inset = options.gutter / 2.0;
+        path = ghostscript_fixed_media_with_timeout(
+            tmp,
+            &path,
+            cell_width - 2.0 * inset,
+            cell_height - 2.0 * inset,
+            &["-dPDFFitPage".to_string()],
+            process_timeout,
+        )
+        .await?;
+        path = ghostscript_fixed_media_with_timeout(
+            tmp,
+            &path,
+            cell_width,
+            cell_height,
+            &[
+                "-c".to_string(),
+                format!("<< /PageOffset [{inset:.2} {inset:.2}] >> setpagedevice"),
+                "-f".to_string(),
+            ],
+            process_timeout,
+        )
+        .await?;
+    }
+
+    let (cols, rows) = options.grid();
+    let path = ghostscript_fixed_media_with_timeout(
+        tmp,
+        &path,
+        options.sheet_width,
+        options.sheet_height,
+        &[format!("-sNupControl={cols}x{rows}")],
+        process_timeout,
+    )
+    .await?;
+    Ok((path, options.sheet_of_pages(&cells)))
+}
+
+pub(crate) async fn ghostscript_blank_page_with_timeout(
+    tmp: &TempDir,
+    width: f64,
+    height: f64,
+    process_timeout: Duration,
+) -> Result<PathBuf, AppError> {
+    ghostscript_page_from_postscript_with_timeout(tmp, width, height, "showpage\n", process_timeout)
+        .await
+}
+
+pub(crate) async fn ghostscript_separator_page_with_timeout(
+    tmp: &TempDir,
+    width: f64,
+    height: f64,
+    title: &str,
+    process_timeout: Duration,
+) -> Result<PathBuf, AppError> {
+    ghostscript_page_from_postscript_with_timeout(
+        tmp,
+        width,
+        height,
+        &separator_postscript(title),
+        process_timeout,
+    )
+    .await
+}
+
+async fn ghostscript_page_from_postscript_with_timeout(
+    tmp: &TempDir,
+    width: f64,
+    height: f64,
+    postscript: &str,
+    process_timeout: Duration,
+) -> Result<PathBuf, AppError> {
+    let ps_path = tmp.path().join(format!("page_{}.ps", uuid::Uuid::new_v4()));
+    tokio::fs::write(&ps_path, postscript)
+        .await
+        .map_err(|e| AppError::Internal(e.to_string()))?;
+    let output_path = tmp
+        .path()
+        .join(format!("page_{}.pdf", uuid::Uuid::new_v4()));
+
+    let mut cmd = Command::new("gs");
+    cmd.arg("-q")
+        .arg("-dNOPAUSE")
+        .arg("-dBATCH")
+        .arg("-sDEVICE=pdfwrite")
+        .arg("-dFIXEDMEDIA")
+        .arg(format!("-dDEVICEWIDTHPOINTS={width:.2}"))
+        .arg(format!("-dDEVICEHEIGHTPOINTS={height:.2}"))
+        .arg(format!("-sOutputFile={}", output_path.to_string_lossy()))
+        .arg(&ps_path);
+
+    let output = output_with_timeout(cmd, process_timeout, "ghostscript").await?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        return Err(AppError::Internal(format!("ghostscript failed: {stderr}")));
+    }
+
+    Ok(output_path)
+}
+
+// pdfwrite pass onto a fixed media size, measuring pages by their CropBox; `args`
+// go right before the input and decide how pages are placed. Images are left for
+// the merge pass to downsample.
+async fn ghostscript_fixed_media_with_timeout(
+    tmp: &TempDir,
+    input_path: &Path,
+    width: f64,
+    height: f64,
+    args: &[String],
+    process_timeout: Duration,
+) -> Result<PathBuf, AppError> {
+    let output_path = tmp
+        .path()
+        .join(format!("media_{}.pdf", uuid::Uuid::new_v4()));
+
+    let mut cmd = Command::new("gs");
+    cmd.arg("-q")
+        .arg("-dNOPAUSE")
+        .arg("-dBATCH")
+        .arg("-sDEVICE=pdfwrite")
+        .arg("-dAutoRotatePages=/None")
+        .arg("-dPassThroughJPEGImages=true")
+        .arg("-dDownsampleColorImages=false")
+        .arg("-dDownsampleGrayImages=false")
+        .arg("-dDownsampleMonoImages=false")
+        .arg("-dUseCropBox")
+        .arg("-dFIXEDMEDIA")
+        .arg(format!("-dDEVICEWIDTHPOINTS={width:.2}"))
+        .arg(format!("-dDEVICEHEIGHTPOINTS={height:.2}"))
+        .arg(format!("-sOutputFile={}", output_path.to_string_lossy()))
+        .args(args)
+        .arg(input_path);
+
+    let output = output_with_timeout(cmd, process_timeout, "ghostscript").await?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        return Err(AppError::Internal(format!("ghostscript failed: {stderr}")));
+    }
+
+    Ok(output_path)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ImageFormat {
+    Png,
+    Jpeg,
+    Tiff,
+}
+
+impl ImageFormat {
+    pub(crate) fn parse(s: &str) -> Result<Self, AppError> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "png" => Ok(Self::Png),
+            "jpeg" | "jpg" => Ok(Self::Jpeg),
+            "tiff" | "tif" => Ok(Self::Tiff),
+            other => Err(AppError::BadRequest(format!(
+                "Invalid format: {other} (expected png, jpeg or tiff)"
+            ))),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ColorMode {
+    Color,
+    Gray,
+    Mono,
+}
+
+impl ColorMode {
+    pub(crate) fn parse(s: &str) -> Result<Self, AppError> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "color" => Ok(Self::Color),
+            "gray" | "grey" => Ok(Self::Gray),
+            "mono" => Ok(Self::Mono),
+            other => Err(AppError::BadRequest(format!(
+                "Invalid color mode: {other} (expected color, gray or mono)"
+            ))),
+        }
+    }
+}
+
+pub(crate) fn image_render_options(
+    format: ImageFormat,
+    color: ColorMode,
+    dpi: u32,
+) -> Result<RenderOptions, AppError> {
+    let (device, extension) = match (format, color) {
+        (ImageFormat::Png, ColorMode::Color) => ("png16m", "png"),
+        (ImageFormat::Png, ColorMode::Gray) => ("pnggray", "png"),
+        (ImageFormat::Png, ColorMode::Mono) => ("pngmono", "png"),
+        (ImageFormat::Jpeg, ColorMode::Color) => ("jpeg", "jpg"),
+        (ImageFormat::Jpeg, ColorMode::Gray) => ("jpeggray", "jpg"),
+        (ImageFormat::Jpeg, ColorMode::Mono) => {
+            return Err(AppError::BadRequest(
+                "JPEG output does not support mono; use png or tiff".to_string(),
+            ))
+        }
+        (ImageFormat::Tiff, ColorMode::Color) => ("tiff24nc", "tif"),
+        (ImageFormat::Tiff, ColorMode::Gray) => ("tiffgray", "tif"),
+        (ImageFormat::Tiff, ColorMode::Mono) => ("tiffg4", "tif"),
+    };
+
+    let mut extra_args = Vec::new();
+    if format == ImageFormat::Jpeg {
+        extra_args.push("-dJPEGQ=90".to_string());
+    }
+
+    Ok(RenderOptions {
+        device,
+        extension,
+        dpi,
+        extra_args,
+    })
+}
+
+pub(crate) struct RenderOptions {
+    pub(crate) device: &'static str,
+    pub(crate) extension: &'static str,
+    pub(crate) dpi: u32,
+    pub(crate) extra_args: Vec<String>,
+}
+
+pub(crate) fn text_render_options() -> RenderOptions {
+    RenderOptions {
+        device: "txtwrite",
+        extension: "txt",
+        dpi: 72,
+        extra_args: Vec::new(),
+    }
+}
+
+// Renders the given pages (sorted, 1-based) to one image file each, returned in
+// the same order.
+pub(crate) async fn render_pages_with_ghostscript_with_timeout(
+    tmp: &TempDir,
+    input_path: &Path,
+    pages: &[usize],
+    options: &RenderOptions,
+    process_timeout: Duration,
+) -> Result<Vec<PathBuf>, AppError> {
+    let run_id = uuid::Uuid::new_v4();
+    let page_list = pages
+        .iter()
+        .map(usize::to_string)
+        .collect::<Vec<_>>()
+        .join(",");
+
+    let mut cmd = Command::new("gs");
+    cmd.arg("-q")
+        .arg("-dNOPAUSE")
+        .arg("-dBATCH")
+        .arg(format!("-sDEVICE={}", options.device))
+        .arg(format!("-r{}", options.dpi))
+        .arg("-dTextAlphaBits=4")
+        .arg("-dGraphicsAlphaBits=4")
+        .arg(format!("-sPageList={page_list}"))
+        .args(&options.extra_args)
+        .arg(format!(
+            "-sOutputFile={}",
+            tmp.path()
+                .join(format!("render_{run_id}_%d.{}", options.extension))
+                .to_string_lossy()
+        ))
+        .arg(input_path);
+
+    let output = output_with_timeout(cmd, process_timeout, "ghostscript").await?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        return Err(AppError::Internal(format!("ghostscript failed: {stderr}")));
+    }
+
+    let mut rendered = Vec::with_capacity(pages.len());
+    for idx in 1..=pages.len() {
+        let path = tmp
+            .path()
+            .join(format!("render_{run_id}_{idx}.{}", options.extension));
+        if !tokio::fs::try_exists(&path).await.unwrap_or(false) {
+            return Err(AppError::Internal(format!(
+                "ghostscript rendered fewer pages than requested ({} of {})",
+                idx - 1,
+                pages.len()
+            )));
+        }
+        rendered.push(path);
+    }
+    Ok(rendered)
+}
+
+pub(crate) fn parse_ocr_language(s: &str) -> Result<String, AppError> {
+    let s = s.trim();
+    let valid = !s.is_empty()
+        && s.len() <= 64
+        && s.split('+').all(|part| {
+            !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        });
+    if !valid {
+        return Err(AppError::BadRequest(format!(
+            "Invalid OCR language: {s} (expected tesseract codes such as eng or eng+deu)"
+        )));
+    }
+    Ok(s.to_string())
+}
+
+const OCR_DPI: u32 = 300;
+const MONO_DPI: u32 = 300;
+
+// Opt-in black-and-white conversion for scans (`mono_raster`). pdfwrite can't
+// reliably threshold sampled images, so every page is rendered to 1-bit at
+// MONO_DPI (a step transfer function does the thresholding; raster devices
+// always apply it) and the CCITT G4 TIFF is wrapped back into a PDF. Vector
+// content, text, links and annotations are lost; OCR can put text back.
+pub(crate) async fn mono_rasterize_with_timeout(
+    tmp: &TempDir,
+    input_path: &Path,
+    threshold: f64,
+    process_timeout: Duration,
+) -> Result<PathBuf, AppError> {
+    let tiff_path = tmp
+        .path()
+        .join(format!("mono_{}.tif", uuid::Uuid::new_v4()));
+
+    // The PDF interpreter calls setpagedevice for every page, so Install
+    // re-applies the transfer function page by page.
+    let mut cmd = Command::new("gs");
+    cmd.arg("-q")
+        .arg("-dNOPAUSE")
+        .arg("-dBATCH")
+        .arg("-sDEVICE=tiffg4")
+        .arg(format!("-r{MONO_DPI}"))
+        .arg("-dUseCropBox")
+        .arg(format!("-sOutputFile={}", tiff_path.to_string_lossy()))
+        .arg("-c")
+        .arg(format!(
+            "<< /Install {{ {{ {:.4} gt {{ 1 }} {{ 0 }} ifelse }} settransfer }} >> setpagedevice",
+            threshold.clamp(0.0, 1.0)
+        ))
+        .arg("-f")
+        .arg(input_path);
+
+    let output = output_with_timeout(cmd, process_timeout, "ghostscript").await?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        return Err(AppError::Internal(format!("ghostscript failed: {stderr}")));
+    }
+
+    // img2pdf embeds the G4 data as is and sizes pages from the TIFF resolution.
+    let out_path = tmp
+        .path()
+        .join(format!("mono_{}.pdf", uuid::Uuid::new_v4()));
+    let mut cmd = Command::new("img2pdf");
+    cmd.arg("-o").arg(&out_path).arg(&tiff_path);
+
+    let output = output_with_timeout(cmd, process_timeout, "img2pdf").await?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        return Err(AppError::Internal(format!("img2pdf failed: {stderr}")));
+    }
+
+    Ok(out_path)
+}
+
+// Adds an invisible OCR text layer to every page that has no extractable text.
+// Returns the new file and the pages that were OCRed.
+pub(crate) async fn ocr_pages_without_text_with_timeout(
+    tmp: &TempDir,
+    input_path: &Path,
+    language: &str,
+    max_pages: usize,
+    process_timeout: Duration,
+) -> Result<(PathBuf, Vec<usize>), AppError> {
+    let total = qpdf_show_npages_with_timeout(input_path, process_timeout).await?;
+    let all_pages: Vec<usize> = (1..=total).collect();
+    let texts = render_pages_with_ghostscript_with_timeout(
+        tmp,
+        input_path,
+        &all_pages,
+        &text_render_options(),
+        process_timeout,
+    )
+    .await?;
+
+    let mut blank = Vec::new();
+    for (page, path) in all_pages.iter().zip(&texts) {
+        let text = tokio::fs::read(path)
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+        if text.iter().all(u8::is_ascii_whitespace) {
+            blank.push(*page);
+        }
+    }
+    if blank.is_empty() {
+        return Ok((input_path.to_path_buf(), blank));
+    }
+    if blank.len() > max_pages {
+        return Err(AppError::BadRequest(format!(
+            "Too many pages need OCR ({}, max {max_pages})",
+            blank.len()
+        )));
+    }
+
+    let images = render_pages_with_ghostscript_with_timeout(
+        tmp,
+        input_path,
+        &blank,
+        &RenderOptions {
+            device: "png16m",
+            extension: "png",
+            dpi: OCR_DPI,
+            // qpdf fits each overlay into the page's TrimBox, which crop sets
+            // to the CropBox; rendering the same box keeps the text in place.
+            extra_args: vec!["-dUseCropBox".to_string()],
+        },
+        process_timeout,
+    )
+    .await?;
+
+    let mut overlays = Vec::with_capacity(blank.len());
+    for (page, image) in blank.iter().zip(&images) {
+        let layer =
+            tesseract_text_layer_with_timeout(tmp, image, language, process_timeout).await?;
+        overlays.push((*page, layer));
+    }
+
+    let out = qpdf_overlay_pages_with_timeout(tmp, input_path, &overlays, process_timeout).await?;
+    Ok((out, blank))
+}
+
+async fn tesseract_text_layer_with_timeout(
+    tmp: &TempDir,
+    image_path: &Path,
+    language: &str,
+    process_timeout: Duration,
+) -> Result<PathBuf, AppError> {
+    let out_base = tmp.path().join(format!("ocr_{}", uuid::Uuid::new_v4()));
+
+    let mut cmd = Command::new("tesseract");
+    cmd.arg(image_path)
+        .arg(&out_base)
+        .arg("-l")
+        .arg(language)
+        .arg("--dpi")
+        .arg(OCR_DPI.to_string())
+        .arg("-c")
+        .arg("textonly_pdf=1")
+        .arg("pdf");
+
+    let output = output_with_timeout(cmd, process_timeout, "tesseract").await?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        if stderr.contains("Failed loading language") {
+            return Err(AppError::BadRequest(format!(
+                "OCR language {language} is not installed"
+            )));
+        }
+        return Err(AppError::Internal(format!("tesseract failed: {stderr}")));
+    }
+
+    Ok(out_base.with_extension("pdf"))
+}
+
+// Overlays page 1 of each given file onto the matching page of the input.
+async fn qpdf_overlay_pages_with_timeout(
+    tmp: &TempDir,
+    input_path: &Path,
+    overlays: &[(usize, PathBuf)],
+    process_timeout: Duration,
+) -> Result<PathBuf, AppError> {
+    let out_path = tmp
+        .path()
+        .join(format!("overlay_out_{}.pdf", uuid::Uuid::new_v4()));
+
+    let mut cmd = Command::new("qpdf");
+    cmd.arg(input_path);
+    for (page, layer) in overlays {
+        cmd.arg("--overlay")
+            .arg(layer)
+            .arg(format!("--to={page}"))
+            .arg("--from=1")
+            .arg("--");
+    }
+    cmd.arg(&out_path);
+
+    let output = output_with_timeout(cmd, process_timeout, "qpdf").await?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        return Err(AppError::Internal(format!("qpdf failed: {stderr}")));
+    }
+
+    Ok(out_path)
+}
+
+async fn output_with_timeout(
+    mut cmd: Command,
+    process_timeout: Duration,
+    what: &str,
+) -> Result<Output, AppError> {
+    cmd.stdin(Stdio::null());
+    cmd.stdout(Stdio::piped());
+    cmd.stderr(Stdio::piped());
+    cmd.kill_on_drop(true);
+    let child = cmd
+        .spawn()
+        .map_err(|e| AppError::Internal(format!("Failed to start {what}: {e}")))?;
+
+    match timeout(process_timeout, child.wait_with_output()).await {
+        Ok(output) => output.map_err(|e| AppError::Internal(format!("{what} failed: {e}"))),
+        Err(_) => Err(AppError::Internal(format!(
+            "{what} timed out after {}s",
+            process_timeout.as_secs()
+        ))),
+    }
+}
+
+fn redact(s: &str, secrets: &[&str]) -> String {
+    let mut out = s.to_string();
+    for secret in secrets.iter().filter(|v| !v.is_empty()) {
+        out = out.replace(secret, "***");
     }
+    out
 }
 
 fn truncate_for_log(s: &str) -> String {
@@ -0,0 +1,157 @@
+use serde_json::{Map, Value};
+
+use crate::error::AppError;
+
+// Helpers for `qpdf --json=2` output and `--update-from-json` input. Objects live
+// under "qpdf"[1] as "obj:N G R" -> {"value": ...}; references are "N G R" strings.
+
+pub(crate) fn invalid(what: &str) -> AppError {
+    AppError::Internal(format!("Unexpected qpdf JSON: {what}"))
+}
+
+pub(crate) fn objects(json: &Value) -> Result<&Map<String, Value>, AppError> {
+    json.get("qpdf")
+        .and_then(|q| q.get(1))
+        .and_then(Value::as_object)
+        .ok_or_else(|| invalid("missing objects"))
+}
+
+// Highest object number in use; new objects in an update must be above it.
+pub(crate) fn max_object_id(json: &Value) -> Result<u64, AppError> {
+    json.get("qpdf")
+        .and_then(|q| q.get(0))
+        .and_then(|h| h.get("maxobjectid"))
+        .and_then(Value::as_u64)
+        .ok_or_else(|| invalid("missing maxobjectid"))
+}
+
+pub(crate) fn pages(json: &Value) -> Result<&Vec<Value>, AppError> {
+    json.get("pages")
+        .and_then(Value::as_array)
+        .ok_or_else(|| invalid("missing pages"))
+}
+
+pub(crate) fn page_ref(page: &Value) -> Result<&str, AppError> {
+    page.get("object")
+        .and_then(Value::as_str)
+        .ok_or_else(|| invalid("page without object"))
+}
+
+pub(crate) fn object_dict<'a>(
+    objects: &'a Map<String, Value>,
+    obj_ref: &str,
+) -> Option<&'a Map<String, Value>> {
+    objects
+        .get(&format!("obj:{obj_ref}"))?
+        .get("value")?
+        .as_object()
+}
+
+pub(crate) fn resolve<'a>(objects: &'a Map<String, Value>, value: &'a Value) -> &'a Value {
+    match value.as_str() {
+        Some(r) if r.ends_with(" R") => objects
+            .get(&format!("obj:{r}"))
+            .and_then(|o| o.get("value"))
+            .unwrap_or(value),
+        _ => value,
+    }
+}
+
+// `--update-from-json` document replacing (or, above the highest object number,
+// adding) the given objects ("obj:N G R" -> value).
+pub(crate) fn update_document(
+    json: &Value,
+    updates: Map<String, Value>,
+) -> Result<Value, AppError> {
+    let header = json
+        .get("qpdf")
+        .and_then(|q| q.get(0))
+        .cloned()
+        .ok_or_else(|| invalid("missing header"))?;
+    let updates: Map<String, Value> = updates
+        .into_iter()
+        .map(|(key, dict)| (key, serde_json::json!({ "value": dict })))
+        .collect();
+    Ok(serde_json::json!({ "qpdf": [header, updates] }))
+}
+
+// The trailer dictionary, which qpdf lists among the objects.
+pub(crate) fn trailer(objects: &Map<String, Value>) -> Option<&Map<String, Value>> {
+    objects.get("trailer")?.get("value")?.as_object()
+}
+
+// Decodes a JSON-encoded PDF string ("u:text" or "b:hex") or name ("/Name").
+pub(crate) fn text(value: &Value) -> Option<String> {
+    let s = value.as_str()?;
+    if let Some(t) = s.strip_prefix("u:") {
+        return Some(t.to_string());
+    }
+    if let Some(name) = s.strip_prefix('/') {
+        return Some(name.to_string());
+    }
+    let hex = s.strip_prefix("b:")?;
+    let bytes: Vec<u8> = (0..hex.len() / 2)
+        .map(|i| u8::from_str_radix(hex.get(2 * i..2 * i + 2)?, 16).ok())
+        .collect::<Option<_>>()?;
+    // Text strings are UTF-16BE or (PDF 2.0) UTF-8 with a BOM, otherwise
+    // PDFDocEncoding.
+    if let Some(utf16) = bytes.strip_prefix(&[0xfe, 0xff]) {
+        let units: Vec<u16> = utf16
+            .chunks_exact(2)
+            .map(|c| u16::from_be_bytes([c[0], c[1]]))
+            .collect();
+        return Some(String::from_utf16_lossy(&units));
+    }
+    if let Some(utf8) = bytes.strip_prefix(&[0xef, 0xbb, 0xbf]) {
+        return Some(String::from_utf8_lossy(utf8).into_owned());
+    }
+    Some(bytes.iter().map(|b| pdf_doc_char(*b)).collect())
+}
+
+// PDFDocEncoding (PDF 32000-1, Annex D.2) matches Latin-1 except for these.
+fn pdf_doc_char(b: u8) -> char {
+    const HIGH: [char; 33] = [
+        '\u{2022}', '\u{2020}', '\u{2021}', '\u{2026}', '\u{2014}', '\u{2013}', '\u{0192}',
+        '\u{2044}', '\u{2039}', '\u{203a}', '\u{2212}', '\u{2030}', '\u{201e}', '\u{201c}',
+        '\u{201d}', '\u{2018}', '\u{2019}', '\u{201a}', '\u{2122}', '\u{fb01}', '\u{fb02}',
+        '\u{0141}', '\u{0152}', '\u{0160}', '\u{0178}', '\u{017d}', '\u{0131}', '\u{0142}',
+        '\u{0153}', '\u{0161}', '\u{017e}', '\u{fffd}', '\u{20ac}',
+    ];
+    match b {
+        0x18 => '\u{02d8}',
+        0x19 => '\u{02c7}',
+        0x1a => '\u{02c6}',
+        0x1b => '\u{02d9}',
+        0x1c => '\u{02dd}',
+        0x1d => '\u{02db}',
+        0x1e => '\u{02da}',
+        0x1f => '\u{02dc}',
+        0x7f | 0xad => '\u{fffd}',
+        0x80..=0xa0 => HIGH[usize::from(b - 0x80)],
+        _ => char::from(b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn decodes_strings_and_names() {
+        assert_eq!(text(&json!("u:Ada")).as_deref(), Some("Ada"));
+        assert_eq!(text(&json!("/Yes")).as_deref(), Some("Yes"));
+        assert_eq!(text(&json!("b:feff00e9")).as_deref(), Some("é"));
+        assert_eq!(text(&json!("b:efbbbfc3a9")).as_deref(), Some("é"));
+        assert_eq!(text(&json!(12)), None);
+    }
+
+    #[test]
+    fn decodes_pdf_doc_encoding() {
+        // Latin-1 below 0x80 and above 0xa0, its own characters in between.
+        assert_eq!(text(&json!("b:41e9")).as_deref(), Some("Aé"));
+        assert_eq!(text(&json!("b:8093a0")).as_deref(), Some("•ﬁ€"));
+        assert_eq!(text(&json!("b:189f")).as_deref(), Some("˘\u{fffd}"));
+    }
+}
@@ -0,0 +1,132 @@
+use crate::error::AppError;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct PageRange {
+    pub(crate) start: usize,
+    pub(crate) end: usize,
+}
+
+impl PageRange {
+    pub(crate) fn label(&self) -> String {
+        if self.start == self.end {
+            self.start.to_string()
+        } else {
+            format!("{}-{}", self.start, self.end)
+        }
+    }
+}
+
+pub(crate) fn parse_page_ranges(spec: &str, npages: usize) -> Result<Vec<PageRange>, AppError> {
+    let mut ranges = Vec::new();
+    for part in spec.split(',') {
+        let part = part.trim();
+        if part.is_empty() {
+            continue;
+        }
+
+        let (start, end) = match part.split_once('-') {
+            Some((a, b)) => (parse_page_token(a, npages)?, parse_page_token(b, npages)?),
+            None => {
+                let p = parse_page_token(part, npages)?;
+                (p, p)
+            }
+        };
+        if start > end {
+            return Err(AppError::BadRequest(format!(
+                "Invalid range {part}: start is after end"
+            )));
+        }
+        ranges.push(PageRange { start, end });
+    }
+
+    if ranges.is_empty() {
+        return Err(AppError::BadRequest("Range spec is empty".to_string()));
+    }
+    Ok(ranges)
+}
+
+pub(crate) fn every_n_pages(n: usize, npages: usize) -> Result<Vec<PageRange>, AppError> {
+    if n == 0 {
+        return Err(AppError::BadRequest(
+            "Page count per part must be at least 1".to_string(),
+        ));
+    }
+    Ok((1..=npages)
+        .step_by(n)
+        .map(|start| PageRange {
+            start,
+            end: start.saturating_add(n - 1).min(npages),
+        })
+        .collect())
+}
+
+fn parse_page_token(token: &str, npages: usize) -> Result<usize, AppError> {
+    let token = token.trim();
+    if token.eq_ignore_ascii_case("z") {
+        return Ok(npages);
+    }
+    let page = token
+        .parse::<usize>()
+        .map_err(|_| AppError::BadRequest(format!("Invalid page number: {token}")))?;
+    if page == 0 || page > npages {
+        return Err(AppError::BadRequest(format!(
+            "Invalid page {page} (max {npages})"
+        )));
+    }
+    Ok(page)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(start: usize, end: usize) -> PageRange {
+        PageRange { start, end }
+    }
+
+    #[test]
+    fn parses_single_pages_and_ranges() {
+        let ranges = parse_page_ranges("1-3, 5,7-z", 9).unwrap();
+        assert_eq!(ranges, vec![range(1, 3), range(5, 5), range(7, 9)]);
+    }
+
+    #[test]
+    fn skips_empty_parts() {
+        let ranges = parse_page_ranges(",2,,", 3).unwrap();
+        assert_eq!(ranges, vec![range(2, 2)]);
+    }
+
+    #[test]
+    fn z_means_last_page() {
+        assert_eq!(parse_page_ranges("Z", 4).unwrap(), vec![range(4, 4)]);
+    }
+
+    #[test]
+    fn rejects_bad_specs() {
+        for spec in ["", " , ", "0", "5", "3-2", "a", "1-", "-2"] {
+            assert!(
+                matches!(parse_page_ranges(spec, 4), Err(AppError::BadRequest(_))),
+                "{spec:?} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn splits_every_n_pages() {
+        assert_eq!(
+            every_n_pages(3, 7).unwrap(),
+            vec![range(1, 3), range(4, 6), range(7, 7)]
+        );
+        assert_eq!(every_n_pages(1, 2).unwrap(), vec![range(1, 1), range(2, 2)]);
+    }
+
+    #[test]
+    fn huge_part_size_gives_one_part() {
+        assert_eq!(every_n_pages(usize::MAX, 5).unwrap(), vec![range(1, 5)]);
+    }
+
+    #[test]
+    fn rejects_zero_part_size() {
+        assert!(matches!(every_n_pages(0, 5), Err(AppError::BadRequest(_))));
+    }
+}
@@ -0,0 +1,613 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::error::AppError;
+use crate::metadata::DocumentMetadata;
+use crate::outline::{outline_pdfmarks, OutlineEntry};
+use crate::ranges::{parse_page_ranges, PageRange};
+use crate::util::parse_number;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum StampPosition {
+    Center,
+    Top,
+    Bottom,
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+impl StampPosition {
+    pub(crate) fn parse(s: &str) -> Result<Self, AppError> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "center" => Ok(Self::Center),
+            "top" => Ok(Self::Top),
+            "bottom" => Ok(Self::Bottom),
+            "top-left" => Ok(Self::TopLeft),
+            "top-right" => Ok(Self::TopRight),
+            "bottom-left" => Ok(Self::BottomLeft),
+            "bottom-right" => Ok(Self::BottomRight),
+            other => Err(AppError::BadRequest(format!(
+                "Invalid position: {other} (expected center, top, bottom, top-left, top-right, bottom-left or bottom-right)"
+            ))),
+        }
+    }
+
+    // PostScript expression leaving the anchor point (x y) on the stack; expects the
+    // page width and height in /W and /H.
+    fn anchor_postscript(self) -> &'static str {
+        match self {
+            Self::Center => "W 2 div H 2 div",
+            Self::Top => "W 2 div H M sub",
+            Self::Bottom => "W 2 div M",
+            Self::TopLeft => "M H M sub",
+            Self::TopRight => "W M sub H M sub",
+            Self::BottomLeft => "M M",
+            Self::BottomRight => "W M sub M",
+        }
+    }
+
+    // How far (as a fraction of the text width) the text is shifted left of the anchor.
+    fn align_fraction(self) -> f64 {
+        match self {
+            Self::Center | Self::Top | Self::Bottom => 0.5,
+            Self::TopLeft | Self::BottomLeft => 0.0,
+            Self::TopRight | Self::BottomRight => 1.0,
+        }
+    }
+}
+
+pub(crate) struct WatermarkOptions {
+    pub(crate) text: String,
+    pub(crate) font_size: f64,
+    pub(crate) opacity: f64,
+    pub(crate) angle: f64,
+    pub(crate) position: StampPosition,
+    // `z` is stored as `usize::MAX` and resolved once the page count is known.
+    pub(crate) pages: Option<Vec<PageRange>>,
+}
+
+impl WatermarkOptions {
+    // Builds the options from the `watermark*` multipart fields; `None` when no
+    // watermark text was sent.
+    pub(crate) fn from_fields(fields: &HashMap<String, String>) -> Result<Option<Self>, AppError> {
+        let text = fields
+            .get("watermark")
+            .map(|v| v.trim().to_string())
+            .unwrap_or_default();
+        if text.is_empty() {
+            if let Some(name) = fields.keys().find(|k| k.as_str() != "watermark") {
+                return Err(AppError::BadRequest(format!(
+                    "{name} requires watermark text"
+                )));
+            }
+            return Ok(None);
+        }
+        if let Some(name) = fields.keys().find(|k| {
+            !matches!(
+                k.as_str(),
+                "watermark"
+                    | "watermark_font_size"
+                    | "watermark_opacity"
+                    | "watermark_angle"
+                    | "watermark_position"
+                    | "watermark_pages"
+            )
+        }) {
+            return Err(AppError::BadRequest(format!(
+                "Unexpected form field: {name}"
+            )));
+        }
+
+        let font_size = parse_number(fields, "watermark_font_size", 60.0, 4.0..=400.0)?;
+        let opacity = parse_number(fields, "watermark_opacity", 0.25, 0.0..=1.0)?;
+        let angle = parse_number(fields, "watermark_angle", 45.0, -360.0..=360.0)?;
+        let position = match fields.get("watermark_position") {
+            Some(v) => StampPosition::parse(v)?,
+            None => StampPosition::Center,
+        };
+        let pages = match fields.get("watermark_pages").map(|v| v.trim()) {
+            Some(spec) if !spec.is_empty() => Some(parse_page_ranges(spec, usize::MAX)?),
+            _ => None,
+        };
+
+        Ok(Some(Self {
+            text,
+            font_size,
+            opacity,
+            angle,
+            position,
+            pages,
+        }))
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct StampingOptions {
+    #[serde(default)]
+    pub(crate) bates: Option<BatesOptions>,
+    #[serde(default)]
+    pub(crate) page_numbers: Option<PageNumberOptions>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct BatesOptions {
+    #[serde(default)]
+    pub(crate) prefix: String,
+    #[serde(default = "default_bates_start")]
+    pub(crate) start: u64,
+    #[serde(default = "default_bates_digits")]
+    pub(crate) digits: usize,
+    #[serde(default = "default_bates_position")]
+    pub(crate) position: StampPosition,
+    #[serde(default = "default_label_font_size")]
+    pub(crate) font_size: f64,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct PageNumberOptions {
+    #[serde(default = "default_page_number_format")]
+    pub(crate) format: String,
+    #[serde(default = "default_page_number_position")]
+    pub(crate) position: StampPosition,
+    #[serde(default = "default_label_font_size")]
+    pub(crate) font_size: f64,
+}
+
+fn default_bates_start() -> u64 {
+    1
+}
+
+fn default_bates_digits() -> usize {
+    6
+}
+
+fn default_bates_position() -> StampPosition {
+    StampPosition::BottomRight
+}
+
+fn default_page_number_format() -> String {
+    "Page {page} of {total}".to_string()
+}
+
+fn default_page_number_position() -> StampPosition {
+    StampPosition::Bottom
+}
+
+fn default_label_font_size() -> f64 {
+    10.0
+}
+
+impl StampingOptions {
+    pub(crate) fn parse(json: &str) -> Result<Self, AppError> {
+        let opts: Self = serde_json::from_str(json)
+            .map_err(|e| AppError::BadRequest(format!("Invalid stamping: {e}")))?;
+
+        if let Some(b) = &opts.bates {
+            if !(1..=12).contains(&b.digits) {
+                return Err(AppError::BadRequest(
+                    "Bates digits must be between 1 and 12".to_string(),
+                ));
+            }
+            if b.prefix.chars().count() > 64 {
+                return Err(AppError::BadRequest(
+                    "Bates prefix is too long (max 64 characters)".to_string(),
+                ));
+            }
+            check_font_size(b.font_size)?;
+        }
+        if let Some(p) = &opts.page_numbers {
+            if p.format.chars().count() > 128 {
+                return Err(AppError::BadRequest(
+                    "Page number format is too long (max 128 characters)".to_string(),
+                ));
+            }
+            check_font_size(p.font_size)?;
+        }
+        Ok(opts)
+    }
+}
+
+fn check_font_size(size: f64) -> Result<(), AppError> {
+    if !(4.0..=72.0).contains(&size) {
+        return Err(AppError::BadRequest(
+            "Stamp font_size must be between 4 and 72".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+pub(crate) struct StampPlan {
+    pub(crate) watermark: Option<WatermarkOptions>,
+    pub(crate) stamping: Option<StampingOptions>,
+    pub(crate) metadata: Option<DocumentMetadata>,
+    pub(crate) outline: Vec<OutlineEntry>,
+}
+
+impl StampPlan {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.watermark.is_none()
+            && self.metadata.is_none()
+            && self.outline.is_empty()
+            && self
+                .stamping
+                .as_ref()
+                .is_none_or(|s| s.bates.is_none() && s.page_numbers.is_none())
+    }
+
+    // Emits a PostScript prologue that installs an EndPage procedure drawing the
+    // stamps on top of every page Ghostscript writes. Per-page labels are
+    // precomputed into arrays indexed by the running page number N.
+    pub(crate) fn to_postscript(&self, total_pages: usize) -> String {
+        let mut defs = String::new();
+        let mut body = String::new();
+        if let Some(w) = &self.watermark {
+            // Ranges starting past the end of the output are left out.
+            let pages = w.pages.as_ref().map(|ranges| {
+                ranges
+                    .iter()
+                    .map(|r| PageRange {
+                        start: if r.start == usize::MAX {
+                            total_pages
+                        } else {
+                            r.start
+                        },
+                        end: r.end.min(total_pages),
+                    })
+                    .filter(|r| r.start <= total_pages)
+                    .collect::<Vec<_>>()
+            });
+            let draw = draw_text(
+                &ps_string(&w.text),
+                w.font_size,
+                w.opacity,
+                w.angle,
+                0.5,
+                w.position,
+            );
+            body.push_str(&guard_pages(pages.as_deref(), &draw));
+            body.push('\n');
+        }
+
+        if let Some(stamping) = &self.stamping {
+            if let Some(b) = &stamping.bates {
+                let labels = (0..total_pages).map(|i| {
+                    let n = b.start.saturating_add(i as u64);
+                    format!("{}{:0width$}", b.prefix, n, width = b.digits)
+                });
+                defs.push_str(&ps_string_array("PTBates", labels));
+                body.push_str(&draw_text(
+                    "PTBates N 1 sub get",
+                    b.font_size,
+                    1.0,
+                    0.0,
+                    0.0,
+                    b.position,
+                ));
+                body.push('\n');
+            }
+            if let Some(p) = &stamping.page_numbers {
+                let labels = (1..=total_pages).map(|n| {
+                    p.format
+                        .replace("{page}", &n.to_string())
+                        .replace("{total}", &total_pages.to_string())
+                });
+                defs.push_str(&ps_string_array("PTPageLabels", labels));
+                body.push_str(&draw_text(
+                    "PTPageLabels N 1 sub get",
+                    p.font_size,
+                    1.0,
+                    0.0,
+                    0.0,
+                    p.position,
+                ));
+                body.push('\n');
+            }
+        }
+
+        // Document-level pdfmarks are emitted from the last page so they win over
+        // whatever the PDF interpreter carries over from the input.
+        let mut doc_marks = String::new();
+        if let Some(meta) = &self.metadata {
+            let _ = writeln!(doc_marks, "{}", meta.to_pdfmark());
+        }
+        doc_marks.push_str(&outline_pdfmarks(&self.outline));
+        if !doc_marks.is_empty() {
+            let _ = writeln!(body, "N {total_pages} eq {{\n{doc_marks}}} if");
+        }
+
+        format!(
+            "%!PS\n\
+             {LATIN1_FONT}\
+             /PTsetalpha {{ /.setfillconstantalpha where \
+             {{ pop .setfillconstantalpha }} \
+             {{ /PTalpha exch def [ /ca PTalpha /CA PTalpha /SetTransparency pdfmark }} ifelse }} bind def\n\
+             {defs}\
+             currentglobal true setglobal globaldict /PTPageNo 0 put setglobal\n\
+             << /EndPage {{\n\
+             exch pop 0 eq dup {{\n\
+             userdict begin\n\
+             globaldict /PTPageNo 2 copy get 1 add put\n\
+             globaldict /PTPageNo get /N exch def\n\
+             currentpagedevice /PageSize get aload pop /H exch def /W exch def /M 36 def\n\
+             {body}\
+             end\n\
+             }} if\n\
+             }} bind >> setpagedevice\n"
+        )
+    }
+}
+
+// Page with `title` centred, used for separator sheets in the merge layout.
+pub(crate) fn separator_postscript(title: &str) -> String {
+    format!(
+        "%!PS\n\
+         {LATIN1_FONT}\
+         currentpagedevice /PageSize get aload pop /H exch def /W exch def\n\
+         /PTTitle {} def\n\
+         /PTFont findfont 1 scalefont setfont PTTitle stringwidth pop /TW exch def\n\
+         /S TW 0 gt {{ W 0.8 mul TW div 36 min }} {{ 36 }} ifelse def\n\
+         /PTFont findfont S scalefont setfont\n\
+         W PTTitle stringwidth pop sub 2 div H 2 div S 3 div sub moveto PTTitle show\n\
+         showpage\n",
+        ps_string(title)
+    )
+}
+
+// Defines /PTFont: Helvetica re-encoded so ps_string's Latin-1 escapes render.
+const LATIN1_FONT: &str = "/PTFont /Helvetica findfont dup length dict begin\n\
+     { 1 index /FID ne { def } { pop pop } ifelse } forall\n\
+     /Encoding ISOLatin1Encoding def currentdict end definefont pop\n";
+
+// `text` is a PostScript expression leaving the string to draw on the stack.
+fn draw_text(
+    text: &str,
+    size: f64,
+    opacity: f64,
+    angle: f64,
+    gray: f64,
+    position: StampPosition,
+) -> String {
+    format!(
+        "gsave initgraphics {opacity} PTsetalpha {gray} setgray /PTFont findfont {size} scalefont setfont \
+         {anchor} translate {angle} rotate {text} dup stringwidth pop {align} mul neg \
+         {size} -0.35 mul moveto show grestore",
+        opacity = ps_number(opacity),
+        gray = ps_number(gray),
+        size = ps_number(size),
+        anchor = position.anchor_postscript(),
+        angle = ps_number(angle),
+        align = ps_number(position.align_fraction()),
+    )
+}
+
+fn ps_string_array(name: &str, items: impl Iterator<Item = String>) -> String {
+    let mut out = format!("/{name} [");
+    for item in items {
+        out.push('\n');
+        out.push_str(&ps_string(&item));
+    }
+    out.push_str("\n] def\n");
+    out
+}
+
+fn guard_pages(pages: Option<&[PageRange]>, draw: &str) -> String {
+    let Some(pages) = pages else {
+        return draw.to_string();
+    };
+    let mut cond = String::from("false");
+    for r in pages {
+        let _ = write!(cond, " N {} ge N {} le and or", r.start, r.end);
+    }
+    format!("{cond} {{ {draw} }} if")
+}
+
+fn ps_number(n: f64) -> String {
+    format!("{n:.4}")
+}
+
+// Encodes text as a PostScript string literal for an ISOLatin1-encoded font;
+// characters outside Latin-1 become '?'.
+pub(crate) fn ps_string(text: &str) -> String {
+    let mut out = String::from("(");
+    for c in text.chars() {
+        let code = u32::from(c);
+        match c {
+            '(' | ')' | '\\' => {
+                out.push('\\');
+                out.push(c);
+            }
+            ' '..='~' => out.push(c),
+            _ if code <= 0xFF => {
+                let _ = write!(out, "\\{code:03o}");
+            }
+            _ => out.push('?'),
+        }
+    }
+    out.push(')');
+    out
+}
+
+// Encodes raw bytes (e.g. a file name) as a PostScript string literal, so they
+// reach the interpreter unchanged.
+pub(crate) fn ps_bytes(bytes: &[u8]) -> String {
+    let mut out = String::from("(");
+    for &b in bytes {
+        match b {
+            b'(' | b')' | b'\\' => {
+                out.push('\\');
+                out.push(char::from(b));
+            }
+            b' '..=b'~' => out.push(char::from(b)),
+            _ => {
+                let _ = write!(out, "\\{b:03o}");
+            }
+        }
+    }
+    out.push(')');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn watermark_plan(pairs: &[(&str, &str)]) -> StampPlan {
+        StampPlan {
+            watermark: WatermarkOptions::from_fields(&fields(pairs)).unwrap(),
+            ..StampPlan::default()
+        }
+    }
+
+    #[test]
+    fn ps_string_escapes_delimiters() {
+        assert_eq!(ps_string(r"a(b)c\d"), r"(a\(b\)c\\d)");
+    }
+
+    #[test]
+    fn ps_string_encodes_latin1_and_replaces_the_rest() {
+        assert_eq!(ps_string("é€\n"), r"(\351?\012)");
+    }
+
+    #[test]
+    fn ps_bytes_keeps_raw_bytes() {
+        assert_eq!(
+            ps_bytes("/icc/fé (1).icc".as_bytes()),
+            r"(/icc/f\303\251 \(1\).icc)"
+        );
+    }
+
+    #[test]
+    fn watermark_defaults() {
+        let w = WatermarkOptions::from_fields(&fields(&[("watermark", " DRAFT ")]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(w.text, "DRAFT");
+        assert_eq!((w.font_size, w.opacity, w.angle), (60.0, 0.25, 45.0));
+        assert_eq!(w.position, StampPosition::Center);
+        assert!(w.pages.is_none());
+
+        assert!(
+            WatermarkOptions::from_fields(&fields(&[("watermark", " ")]))
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn rejects_bad_watermark_fields() {
+        for pairs in [
+            vec![("watermark_opacity", "0.5")],
+            vec![("watermark", "x"), ("watermark_color", "red")],
+            vec![("watermark", "x"), ("watermark_opacity", "2")],
+            vec![("watermark", "x"), ("watermark_position", "middle")],
+            vec![("watermark", "x"), ("watermark_pages", "0")],
+            vec![("watermark", "x"), ("watermark_pages", "3-1")],
+            vec![("watermark", "x"), ("watermark_pages", "odd")],
+        ] {
+            assert!(
+                matches!(
+                    WatermarkOptions::from_fields(&fields(&pairs)),
+                    Err(AppError::BadRequest(_))
+                ),
+                "{pairs:?} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn watermark_draws_escaped_text() {
+        let ps = watermark_plan(&[("watermark", "(c) ACME"), ("watermark_opacity", "0.5")])
+            .to_postscript(3);
+        assert!(ps.contains(r"\(c\) ACME"));
+        assert!(ps.contains("0.5000 PTsetalpha 0.5000 setgray"));
+        assert!(ps.contains("45.0000 rotate"));
+        assert!(!ps.contains("false N"));
+    }
+
+    #[test]
+    fn watermark_pages_resolve_against_the_output() {
+        let plan = watermark_plan(&[("watermark", "x"), ("watermark_pages", "2, 4-z, 9")]);
+        let ps = plan.to_postscript(5);
+        assert!(ps.contains("false N 2 ge N 2 le and or N 4 ge N 5 le and or {"));
+        assert!(!ps.contains("N 9 ge"));
+
+        let ps = plan.to_postscript(3);
+        assert!(ps.contains("false N 2 ge N 2 le and or {"));
+
+        let ps = watermark_plan(&[("watermark", "x"), ("watermark_pages", "z")]).to_postscript(7);
+        assert!(ps.contains("false N 7 ge N 7 le and or {"));
+    }
+
+    fn stamping_plan(json: &str) -> StampPlan {
+        StampPlan {
+            stamping: Some(StampingOptions::parse(json).unwrap()),
+            ..StampPlan::default()
+        }
+    }
+
+    #[test]
+    fn bates_labels_are_prefixed_and_zero_padded() {
+        let ps = stamping_plan(r#"{"bates": {"prefix": "ACME-", "start": 98, "digits": 3}}"#)
+            .to_postscript(3);
+        assert!(ps.contains("/PTBates [\n(ACME-098)\n(ACME-099)\n(ACME-100)\n] def\n"));
+        assert!(ps.contains("PTBates N 1 sub get"));
+    }
+
+    #[test]
+    fn bates_defaults() {
+        let ps = stamping_plan(r#"{"bates": {}}"#).to_postscript(2);
+        assert!(ps.contains("/PTBates [\n(000001)\n(000002)\n] def\n"));
+        assert!(ps.contains("W M sub M translate"));
+    }
+
+    #[test]
+    fn bates_numbers_wider_than_digits_are_kept() {
+        let ps = stamping_plan(r#"{"bates": {"start": 99, "digits": 1}}"#).to_postscript(2);
+        assert!(ps.contains("/PTBates [\n(99)\n(100)\n] def\n"));
+    }
+
+    #[test]
+    fn page_number_labels_fill_in_page_and_total() {
+        let ps = stamping_plan(r#"{"page_numbers": {"format": "{page}/{total} (draft)"}}"#)
+            .to_postscript(2);
+        assert!(ps.contains("/PTPageLabels [\n(1/2 \\(draft\\))\n(2/2 \\(draft\\))\n] def\n"));
+        assert!(ps.contains("W 2 div M translate"));
+    }
+
+    #[test]
+    fn rejects_bad_stamping() {
+        for json in [
+            r#"{"bates": {"digits": 0}}"#,
+            r#"{"bates": {"digits": 13}}"#,
+            r#"{"bates": {"font_size": 100}}"#,
+            r#"{"bates": {"suffix": "x"}}"#,
+            r#"{"page_numbers": {"position": "middle"}}"#,
+            r#"{"footer": {}}"#,
+            "null",
+        ] {
+            assert!(
+                matches!(StampingOptions::parse(json), Err(AppError::BadRequest(_))),
+                "{json} should be rejected"
+            );
+        }
+        let long_prefix = format!(r#"{{"bates": {{"prefix": "{}"}}}}"#, "x".repeat(65));
+        assert!(matches!(
+            StampingOptions::parse(&long_prefix),
+            Err(AppError::BadRequest(_))
+        ));
+    }
+}
@@ -1,3 +1,4 @@
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration as StdDuration;
 
@@ -15,6 +16,8 @@ pub(crate) struct AppState {
     pub(crate) signer: Arc<SessionSigner>,
     pub(crate) cookie: Arc<CookieConfig>,
     pub(crate) process_timeout: StdDuration,
+    pub(crate) pdfa_icc_profile: Arc<PathBuf>,
+    pub(crate) verapdf_path: Option<Arc<PathBuf>>,
 }
 
 pub(crate) struct AuthConfig {
@@ -28,6 +31,7 @@ pub(crate) struct CookieConfig {
 }
 
 impl AppState {
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         username: String,
         password: String,
@@ -36,6 +40,8 @@ impl AppState {
         process_timeout: StdDuration,
         cookie_secure: CookieSecureMode,
         trust_proxy_headers: bool,
+        pdfa_icc_profile: PathBuf,
+        verapdf_path: Option<PathBuf>,
     ) -> Self {
         Self {
             auth: Arc::new(AuthConfig { username, password }),
@@ -45,6 +51,8 @@ impl AppState {
                 trust_proxy_headers,
             }),
             process_timeout,
+            pdfa_icc_profile: Arc::new(pdfa_icc_profile),
+            verapdf_path: verapdf_path.map(Arc::new),
         }
     }
 
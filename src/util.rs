@@ -1,4 +1,52 @@
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+
+use crate::error::AppError;
+
 pub(crate) fn parse_bool_loose(s: &str) -> bool {
     let v = s.trim().to_ascii_lowercase();
     matches!(v.as_str(), "1" | "true" | "on" | "yes")
 }
+
+pub(crate) fn parse_number(
+    fields: &HashMap<String, String>,
+    name: &str,
+    default: f64,
+    bounds: RangeInclusive<f64>,
+) -> Result<f64, AppError> {
+    let Some(value) = fields.get(name) else {
+        return Ok(default);
+    };
+    let n = value
+        .trim()
+        .parse::<f64>()
+        .map_err(|_| AppError::BadRequest(format!("Invalid {name}")))?;
+    if !bounds.contains(&n) {
+        return Err(AppError::BadRequest(format!(
+            "{name} must be between {} and {}",
+            bounds.start(),
+            bounds.end()
+        )));
+    }
+    Ok(n)
+}
+
+// Portrait width and height in points for a named paper size or `WxH` in points.
+pub(crate) fn paper_size_points(s: &str) -> Option<(f64, f64)> {
+    let size = match s.trim().to_ascii_lowercase().as_str() {
+        "a3" => (842.0, 1191.0),
+        "a4" => (595.0, 842.0),
+        "a5" => (420.0, 595.0),
+        "letter" => (612.0, 792.0),
+        "legal" => (612.0, 1008.0),
+        "tabloid" => (792.0, 1224.0),
+        other => {
+            let (w, h) = other.split_once('x')?;
+            let w = w.trim().parse::<f64>().ok()?;
+            let h = h.trim().parse::<f64>().ok()?;
+            (w, h)
+        }
+    };
+    let valid = 72.0..=14400.0;
+    (valid.contains(&size.0) && valid.contains(&size.1)).then_some(size)
+}
@@ -1,6 +1,8 @@
 (() => {
   const MAX_FILES = 10;
   const MAX_FILE_BYTES = 30 * 1024 * 1024;
+  const ACCEPTED_EXTENSIONS = [".pdf", ".jpg", ".jpeg", ".png", ".tif", ".tiff"];
+  const ACCEPTED_TYPES = ["application/pdf", "image/jpeg", "image/png", "image/tiff"];
 
   const dropzone = document.getElementById("dropzone");
   const fileInput = document.getElementById("fileInput");
@@ -410,12 +412,13 @@
   function addFiles(fileListObj) {
     const files = Array.from(fileListObj);
     const accepted = files.filter((f) => {
-      const nameOk = f.name.toLowerCase().endsWith(".pdf");
-      const typeOk = !f.type || f.type === "application/pdf";
+      const name = f.name.toLowerCase();
+      const nameOk = ACCEPTED_EXTENSIONS.some((ext) => name.endsWith(ext));
+      const typeOk = !f.type || ACCEPTED_TYPES.includes(f.type);
       return nameOk && typeOk;
     });
     if (accepted.length !== files.length) {
-      showToast("Some files were skipped (only PDFs are allowed).");
+      showToast("Some files were skipped (only PDFs and JPEG/PNG/TIFF images are allowed).");
     }
     if (accepted.length === 0) return;
 